@@ -0,0 +1,576 @@
+//! The `.brc` format for compiled programs, which stores the optimised instructions along with
+//! metadata about the program.
+//!
+//! The source hash is a little-endian `u64`, and all other integers are unsigned LEB128 varints.
+//! Strings are prefixed with their length. Optional fields are prefixed with a byte that is 1 if
+//! the field is present, and 0 otherwise.
+//!
+//! ```text
+//! magic        b"\x89BRC"
+//! version      u8
+//! source hash  u64 (FNV-1a)
+//! dialect      string
+//! config       cell width in bits (u8), tape size, EOF (u8), tape wrapping (u8)
+//! author       optional string
+//! comment      optional string
+//! debug info   optional: file name (string), count, line and column per op
+//! ops          count, opcode (u8) per op followed by the operand if any
+//! ```
+//!
+//! Programs compiled by older versions of the interpreter are migrated when they are decoded:
+//!
+//! - version 0 has the same layout, but encodes integers as little-endian `u32`s, except for the
+//!   tape size and the operands which are `u64`s
+//! - versions 0 and 1 were also written with the legacy magic `b"BRC"`
+
+use std::fmt;
+
+use crate::{
+    parse::{Jump, Op},
+    CellWidth, Config, Cpu, Eof,
+};
+
+/// The leading byte is not valid UTF-8, so a source (which may start with any text as a comment)
+/// is never mistaken for bytecode.
+const MAGIC: &[u8; 4] = b"\x89BRC";
+/// The magic written by older versions of the interpreter, which is only recognised along with the
+/// versions that were written with it, since a source could start with it.
+const LEGACY_MAGIC: &[u8; 3] = b"BRC";
+const LEGACY_VERSIONS: [u8; 2] = [0, 1];
+/// The version of the format written by this version of the interpreter. Any older version can
+/// still be decoded.
+pub const VERSION: u8 = 1;
+/// The largest tape accepted from bytecode, since the tape is allocated upfront.
+const MAX_TAPE_SIZE: usize = 1 << 28;
+
+/// Location of each instruction in the source, so that compiled programs can be traced back to
+/// their source.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct DebugInfo {
+    pub file: String,
+    /// Line and column (both starting at 1) of each instruction.
+    pub locations: Vec<(u32, u32)>,
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Metadata {
+    pub source_hash: u64,
+    /// The compatibility preset that the program was compiled for, or `default`.
+    pub dialect: String,
+    /// The cell width and tape requirements of the program.
+    pub config: Config,
+    pub author: Option<String>,
+    pub comment: Option<String>,
+    pub debug_info: Option<DebugInfo>,
+}
+
+/// A compiled program.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Bytecode {
+    pub metadata: Metadata,
+    ops: Vec<Op>,
+}
+
+impl Bytecode {
+    /// Compiles the source into bytecode, with the given metadata. The source hash and debug info
+    /// are filled in from the source, and the file name if provided.
+    pub fn new(src: &str, config: Config, dialect: &str, file: Option<&str>) -> Self {
+        let (ops, offsets) = crate::compile(src);
+        let debug_info = file.map(|file| DebugInfo {
+            file: file.into(),
+            locations: locations(src, &offsets),
+        });
+        Self {
+            metadata: Metadata {
+                source_hash: fnv1a(src.as_bytes()),
+                dialect: dialect.into(),
+                config,
+                author: None,
+                comment: None,
+                debug_info,
+            },
+            ops,
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        self.ops.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.ops.is_empty()
+    }
+
+    /// Checks whether the bytes start with the magic of the bytecode format.
+    pub fn is_bytecode(bytes: &[u8]) -> bool {
+        bytes.starts_with(MAGIC) || header(bytes).is_some()
+    }
+
+    /// The version of the format that the bytecode was encoded with.
+    pub fn version(bytes: &[u8]) -> Option<u8> {
+        header(bytes).map(|(version, _)| version)
+    }
+
+    /// Encodes the bytecode with the current version of the format.
+    pub fn encode(&self) -> Vec<u8> {
+        let mut w = Writer([&MAGIC[..], &[VERSION]].concat());
+        let m = &self.metadata;
+        w.u64(m.source_hash);
+        w.str(&m.dialect);
+        w.config(&m.config);
+        w.opt(m.author.as_ref(), |w, s| w.str(s));
+        w.opt(m.comment.as_ref(), |w, s| w.str(s));
+        w.opt(m.debug_info.as_ref(), |w, d| {
+            w.str(&d.file);
+            w.varint(d.locations.len() as u64);
+            for &(line, col) in &d.locations {
+                w.varint(line as u64);
+                w.varint(col as u64);
+            }
+        });
+        w.varint(self.ops.len() as u64);
+        for op in &self.ops {
+            let (opcode, operand) = match *op {
+                Op::Increment(i) => (0, Some(i)),
+                Op::Decrement(i) => (1, Some(i)),
+                Op::MoveR(i) => (2, Some(i)),
+                Op::MoveL(i) => (3, Some(i)),
+                Op::Jump(Jump::JumpR(r)) => (4, Some(r)),
+                Op::Jump(Jump::JumpL(l)) => (5, Some(l)),
+                Op::Set => (6, None),
+                Op::Get => (7, None),
+                Op::Debug => (8, None),
+                Op::Clear => (9, None),
+                Op::Empty => unreachable!("this should never have made it past the optimisations"),
+            };
+            w.u8(opcode);
+            if let Some(operand) = operand {
+                w.varint(operand as u64);
+            }
+        }
+        w.0
+    }
+
+    /// Decodes bytecode encoded with the current or any older version of the format.
+    pub fn decode(bytes: &[u8]) -> Result<Self, String> {
+        let (version, pos) = header(bytes).ok_or("not a compiled brainrot program")?;
+        if version > VERSION {
+            return Err(format!(
+                "compiled with newer brainrot (bytecode version {version}, \
+                 this version supports up to {VERSION}), please recompile"
+            ));
+        }
+        let mut r = Reader {
+            bytes,
+            pos,
+            version,
+        };
+        let metadata = Metadata {
+            source_hash: r.u64()?,
+            dialect: r.str()?,
+            config: r.config()?,
+            author: r.opt(Reader::str)?,
+            comment: r.opt(Reader::str)?,
+            debug_info: r.opt(|r| {
+                let file = r.str()?;
+                let locations = (0..r.u32()?)
+                    .map(|_| Ok((r.u32()?, r.u32()?)))
+                    .collect::<Result<_, String>>()?;
+                Ok(DebugInfo { file, locations })
+            })?,
+        };
+        let ops = (0..r.u32()?)
+            .map(|_| {
+                Ok(match r.u8()? {
+                    0 => Op::Increment(r.usize()?),
+                    1 => Op::Decrement(r.usize()?),
+                    2 => Op::MoveR(r.usize()?),
+                    3 => Op::MoveL(r.usize()?),
+                    4 => Op::Jump(Jump::JumpR(r.usize()?)),
+                    5 => Op::Jump(Jump::JumpL(r.usize()?)),
+                    6 => Op::Set,
+                    7 => Op::Get,
+                    8 => Op::Debug,
+                    9 => Op::Clear,
+                    opcode => return Err(format!("invalid opcode {opcode}")),
+                })
+            })
+            .collect::<Result<Vec<_>, String>>()?;
+        if r.pos < bytes.len() {
+            return Err("trailing bytes after the instructions".into());
+        }
+        // Jump targets are used as is, so they must be within the program
+        if ops
+            .iter()
+            .any(|op| matches!(op, Op::Jump(Jump::JumpR(j) | Jump::JumpL(j)) if *j > ops.len()))
+        {
+            return Err("jump target outside of the program".into());
+        }
+        Ok(Self { metadata, ops })
+    }
+
+    pub fn exec<R: std::io::Read, W: std::io::Write>(&self, cpu: &mut Cpu<R, W>) {
+        cpu.exec(self.ops.clone());
+    }
+}
+
+/// Returns the version of the format along with the length of the header, which is the magic
+/// followed by the version.
+fn header(bytes: &[u8]) -> Option<(u8, usize)> {
+    if let Some(&version) = bytes.strip_prefix(MAGIC).and_then(<[u8]>::first) {
+        return Some((version, MAGIC.len() + 1));
+    }
+    match bytes.strip_prefix(LEGACY_MAGIC)?.first() {
+        Some(&version) if LEGACY_VERSIONS.contains(&version) => {
+            Some((version, LEGACY_MAGIC.len() + 1))
+        }
+        _ => None,
+    }
+}
+
+/// FNV-1a hash, which is simple and good enough to detect that a program was compiled from a
+/// different source.
+fn fnv1a(bytes: &[u8]) -> u64 {
+    bytes.iter().fold(0xcbf29ce484222325, |hash, &b| {
+        (hash ^ b as u64).wrapping_mul(0x100000001b3)
+    })
+}
+
+/// Line and column of each offset in the source. The offsets must be in increasing order.
+fn locations(src: &str, offsets: &[usize]) -> Vec<(u32, u32)> {
+    let (mut line, mut col, mut prev) = (1, 1, 0);
+    offsets
+        .iter()
+        .map(|&offset| {
+            for c in src[prev..offset].chars() {
+                if c == '\n' {
+                    (line, col) = (line + 1, 1);
+                } else {
+                    col += 1;
+                }
+            }
+            prev = offset;
+            (line, col)
+        })
+        .collect()
+}
+
+struct Writer(Vec<u8>);
+
+impl Writer {
+    fn u8(&mut self, v: u8) {
+        self.0.push(v);
+    }
+
+    fn u64(&mut self, v: u64) {
+        self.0.extend_from_slice(&v.to_le_bytes());
+    }
+
+    fn varint(&mut self, mut v: u64) {
+        while v >= 0x80 {
+            self.u8(v as u8 | 0x80);
+            v >>= 7;
+        }
+        self.u8(v as u8);
+    }
+
+    fn str(&mut self, s: &str) {
+        self.varint(s.len() as u64);
+        self.0.extend_from_slice(s.as_bytes());
+    }
+
+    fn opt<T>(&mut self, v: Option<T>, f: impl FnOnce(&mut Self, T)) {
+        match v {
+            Some(v) => {
+                self.u8(1);
+                f(self, v);
+            }
+            None => self.u8(0),
+        }
+    }
+
+    fn config(&mut self, config: &Config) {
+        self.u8(match config.cell_width {
+            CellWidth::U8 => 8,
+            CellWidth::U16 => 16,
+            CellWidth::U32 => 32,
+        });
+        self.varint(config.tape_size as u64);
+        self.u8(match config.eof {
+            Eof::Zero => 0,
+            Eof::MinusOne => 1,
+            Eof::Unchanged => 2,
+        });
+        self.u8(config.wrap_tape as u8);
+    }
+}
+
+struct Reader<'a> {
+    bytes: &'a [u8],
+    pos: usize,
+    /// The version of the format, which determines how integers are encoded.
+    version: u8,
+}
+
+impl Reader<'_> {
+    fn take<const N: usize>(&mut self) -> Result<[u8; N], String> {
+        let bytes = self
+            .bytes
+            .get(self.pos..self.pos + N)
+            .ok_or("unexpected end of bytecode")?;
+        self.pos += N;
+        Ok(bytes.try_into().unwrap())
+    }
+
+    fn u8(&mut self) -> Result<u8, String> {
+        Ok(self.take::<1>()?[0])
+    }
+
+    fn u64(&mut self) -> Result<u64, String> {
+        Ok(u64::from_le_bytes(self.take()?))
+    }
+
+    fn varint(&mut self) -> Result<u64, String> {
+        let mut v = 0;
+        for shift in (0..64).step_by(7) {
+            let b = self.u8()?;
+            if shift == 63 && b > 1 {
+                break;
+            }
+            v |= ((b & 0x7f) as u64) << shift;
+            if b & 0x80 == 0 {
+                return Ok(v);
+            }
+        }
+        Err("varint too large".into())
+    }
+
+    /// A count, length, or source location.
+    fn u32(&mut self) -> Result<u32, String> {
+        match self.version {
+            0 => Ok(u32::from_le_bytes(self.take()?)),
+            _ => self
+                .varint()?
+                .try_into()
+                .map_err(|_| "integer too large".into()),
+        }
+    }
+
+    /// A tape size or an operand.
+    fn usize(&mut self) -> Result<usize, String> {
+        match self.version {
+            0 => self.u64()?,
+            _ => self.varint()?,
+        }
+        .try_into()
+        .map_err(|_| "operand too large for this platform".into())
+    }
+
+    fn str(&mut self) -> Result<String, String> {
+        let len = self.u32()? as usize;
+        let bytes = self
+            .bytes
+            .get(self.pos..self.pos + len)
+            .ok_or("unexpected end of bytecode")?;
+        self.pos += len;
+        String::from_utf8(bytes.to_vec()).map_err(|_| "invalid string in bytecode".into())
+    }
+
+    fn opt<T>(
+        &mut self,
+        f: impl FnOnce(&mut Self) -> Result<T, String>,
+    ) -> Result<Option<T>, String> {
+        match self.u8()? {
+            0 => Ok(None),
+            1 => f(self).map(Some),
+            _ => Err("invalid optional field".into()),
+        }
+    }
+
+    fn config(&mut self) -> Result<Config, String> {
+        Ok(Config {
+            cell_width: match self.u8()? {
+                8 => CellWidth::U8,
+                16 => CellWidth::U16,
+                32 => CellWidth::U32,
+                w => return Err(format!("invalid cell width {w}")),
+            },
+            tape_size: match self.usize()? {
+                n @ 1..=MAX_TAPE_SIZE => n,
+                n => {
+                    return Err(format!(
+                        "invalid tape size {n}, expected between 1 and {MAX_TAPE_SIZE} cells"
+                    ))
+                }
+            },
+            eof: match self.u8()? {
+                0 => Eof::Zero,
+                1 => Eof::MinusOne,
+                2 => Eof::Unchanged,
+                e => return Err(format!("invalid EOF behaviour {e}")),
+            },
+            wrap_tape: self.u8()? != 0,
+        })
+    }
+}
+
+impl fmt::Display for Metadata {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let c = &self.config;
+        writeln!(f, "Source hash:  {:016x}", self.source_hash)?;
+        writeln!(f, "Dialect:      {}", self.dialect)?;
+        writeln!(f, "Cell width:   {} bits", c.cell_width.mask().count_ones())?;
+        writeln!(f, "Tape size:    {} cells", c.tape_size)?;
+        writeln!(
+            f,
+            "EOF:          {}",
+            match c.eof {
+                Eof::Zero => "0",
+                Eof::MinusOne => "-1",
+                Eof::Unchanged => "unchanged",
+            }
+        )?;
+        writeln!(
+            f,
+            "Tape wraps:   {}",
+            if c.wrap_tape { "yes" } else { "no" }
+        )?;
+        if let Some(author) = &self.author {
+            writeln!(f, "Author:       {author}")?;
+        }
+        if let Some(comment) = &self.comment {
+            writeln!(f, "Comment:      {comment}")?;
+        }
+        match &self.debug_info {
+            Some(d) => writeln!(
+                f,
+                "Debug info:   {} ({} locations)",
+                d.file,
+                d.locations.len()
+            ),
+            None => writeln!(f, "Debug info:   none"),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Bytecode, VERSION};
+    use crate::{Compat, Config, Cpu};
+
+    fn bytecode() -> Bytecode {
+        let mut bytecode = Bytecode::new(
+            "+++\n[>++<-]>.\n",
+            Compat::Bfc.config(),
+            "bfc",
+            Some("double.b"),
+        );
+        bytecode.metadata.author = Some("Brainrot".into());
+        bytecode
+    }
+
+    #[test]
+    fn roundtrip() {
+        let bytecode = bytecode();
+        assert_eq!(Bytecode::decode(&bytecode.encode()).as_ref(), Ok(&bytecode));
+        let debug_info = bytecode.metadata.debug_info.as_ref().unwrap();
+        assert_eq!(debug_info.locations[..3], [(1, 1), (2, 1), (2, 2)]);
+
+        let mut cpu = Cpu::with_io(Config::default(), &b""[..], Vec::new());
+        bytecode.exec(&mut cpu);
+        assert_eq!(cpu.output(), &[6]);
+    }
+
+    /// Checks that a program compiled by an older version with
+    /// `bri --compat bfc compile abc.b --author Brainrot` still decodes and runs. The fixtures are
+    /// checked in exactly as they were written, and must never be regenerated.
+    fn migrate(bytes: &[u8], version: u8) {
+        assert_eq!(Bytecode::version(bytes), Some(version));
+        let bytecode = Bytecode::decode(bytes).unwrap();
+        let src = include_str!("testdata/abc.b");
+        let mut expected = Bytecode::new(src, Compat::Bfc.config(), "bfc", Some("abc.b"));
+        expected.metadata.author = Some("Brainrot".into());
+        // The instructions are not compared, as they depend on the optimisations at the time
+        assert_eq!(bytecode.metadata, expected.metadata);
+
+        let mut cpu = Cpu::with_io(bytecode.metadata.config.clone(), &b""[..], Vec::new());
+        bytecode.exec(&mut cpu);
+        assert_eq!(cpu.output(), b"ABC");
+
+        // Re-encoding upgrades to the current version
+        let bytes = bytecode.encode();
+        assert_eq!(Bytecode::version(&bytes), Some(VERSION));
+        assert_eq!(Bytecode::decode(&bytes), Ok(bytecode));
+    }
+
+    #[test]
+    fn migrate_v0() {
+        migrate(include_bytes!("testdata/abc-v0.brc"), 0);
+    }
+
+    #[test]
+    fn migrate_v1() {
+        migrate(include_bytes!("testdata/abc-v1.brc"), 1);
+    }
+
+    #[test]
+    fn legacy_magic() {
+        // Written by version 0 before the magic gained its leading byte
+        let bytes = include_bytes!("testdata/abc-v0.brc");
+        assert!(bytes.starts_with(b"BRC\0"));
+        assert!(Bytecode::is_bytecode(bytes));
+        assert!(Bytecode::decode(bytes).is_ok());
+        // Only the versions written with the legacy magic are recognised
+        for src in ["BRC\x02+.", "BRC +."] {
+            assert!(!Bytecode::is_bytecode(src.as_bytes()));
+        }
+    }
+
+    #[test]
+    fn source_with_magic_text() {
+        let src = "BRC style header comment\n++++++++[>++++++++<-]>+.";
+        assert!(!Bytecode::is_bytecode(src.as_bytes()));
+        assert_eq!(
+            Bytecode::decode(src.as_bytes()),
+            Err("not a compiled brainrot program".into())
+        );
+        let mut cpu = Cpu::with_io(Config::default(), &b""[..], Vec::new());
+        crate::run(src, &mut cpu);
+        assert_eq!(cpu.output(), b"A");
+    }
+
+    #[test]
+    fn invalid() {
+        let bytes = bytecode().encode();
+        assert_eq!(
+            Bytecode::decode(b"+++"),
+            Err("not a compiled brainrot program".into())
+        );
+        assert_eq!(
+            Bytecode::decode(&bytes[..bytes.len() - 1]),
+            Err("unexpected end of bytecode".into())
+        );
+        let mut bytes = bytes;
+        bytes.push(0);
+        assert_eq!(
+            Bytecode::decode(&bytes),
+            Err("trailing bytes after the instructions".into())
+        );
+        bytes[4] = VERSION + 1;
+        assert!(Bytecode::decode(&bytes)
+            .is_err_and(|e| e.starts_with("compiled with newer brainrot")
+                && e.ends_with("please recompile")));
+
+        for tape_size in [0, super::MAX_TAPE_SIZE + 1] {
+            let mut bytecode = bytecode();
+            bytecode.metadata.config.tape_size = tape_size;
+            assert_eq!(
+                Bytecode::decode(&bytecode.encode()),
+                Err(format!(
+                    "invalid tape size {tape_size}, expected between 1 and {} cells",
+                    super::MAX_TAPE_SIZE
+                ))
+            );
+        }
+    }
+}
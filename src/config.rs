@@ -0,0 +1,122 @@
+use std::str::FromStr;
+
+const DEFAULT_TAPE_SIZE: usize = 30_000;
+
+/// Width of a single memory cell.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum CellWidth {
+    U8,
+    U16,
+    U32,
+}
+
+impl CellWidth {
+    /// Mask applied to cell values after every arithmetic operation, which wraps them
+    /// around at the cell width.
+    pub fn mask(self) -> u32 {
+        match self {
+            Self::U8 => u8::MAX as u32,
+            Self::U16 => u16::MAX as u32,
+            Self::U32 => u32::MAX,
+        }
+    }
+}
+
+/// Value stored in the current cell when input is requested after it has been exhausted.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Eof {
+    Zero,
+    MinusOne,
+    Unchanged,
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Config {
+    pub tape_size: usize,
+    pub cell_width: CellWidth,
+    pub eof: Eof,
+    /// Whether moving past either end of the tape wraps around to the other end. If not set,
+    /// doing so is an error.
+    pub wrap_tape: bool,
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Self {
+            tape_size: DEFAULT_TAPE_SIZE,
+            cell_width: CellWidth::U8,
+            eof: Eof::Zero,
+            wrap_tape: false,
+        }
+    }
+}
+
+/// Presets that match the documented behaviour of other well-known interpreters, so that
+/// programs written against them run identically here.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Compat {
+    Bff,
+    Beef,
+    Bfc,
+    Tritium,
+}
+
+impl Compat {
+    pub fn config(self) -> Config {
+        match self {
+            // bff by Oleg Mazonka (http://mazonka.com/brainf/): 8-bit cells, and a 65,536-cell
+            // tape indexed by a 16-bit pointer, which wraps around at either end. `,` stores -1 on
+            // EOF.
+            Self::Bff => Config {
+                tape_size: 65_536,
+                cell_width: CellWidth::U8,
+                eof: Eof::MinusOne,
+                wrap_tape: true,
+            },
+            // beef(1) (https://kiyuko.org/software/beef): 8-bit cells on the customary 30,000-cell
+            // tape, and `--store` defaults to `same`, which leaves the cell unchanged on EOF.
+            Self::Beef => Config {
+                tape_size: DEFAULT_TAPE_SIZE,
+                cell_width: CellWidth::U8,
+                eof: Eof::Unchanged,
+                wrap_tape: false,
+            },
+            // bfc by Wilfred Hughes (https://github.com/Wilfred/bfc, see its README): 100,000 8-bit
+            // cells, and reading at EOF leaves the cell unchanged.
+            Self::Bfc => Config {
+                tape_size: 100_000,
+                cell_width: CellWidth::U8,
+                eof: Eof::Unchanged,
+                wrap_tape: false,
+            },
+            // tritium by Robert de Bath (https://github.com/rdebath/Brainfuck, see `tritium -h`):
+            // 8-bit cells by default (wider cells are opt-in with `-b16` and `-b32`), and EOF
+            // leaves the cell unchanged by default. The tape grows on demand, which is
+            // approximated with 1,048,576 cells.
+            Self::Tritium => Config {
+                tape_size: 1 << 20,
+                cell_width: CellWidth::U8,
+                eof: Eof::Unchanged,
+                wrap_tape: false,
+            },
+        }
+    }
+}
+
+impl FromStr for Compat {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        Ok(match s {
+            "bff" => Self::Bff,
+            "beef" => Self::Beef,
+            "bfc" => Self::Bfc,
+            "tritium" => Self::Tritium,
+            _ => {
+                return Err(format!(
+                    "unknown compatibility preset `{s}`, expected one of: bff, beef, bfc, tritium"
+                ))
+            }
+        })
+    }
+}
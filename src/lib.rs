@@ -1,57 +1,129 @@
+mod bytecode;
+mod config;
 mod optimise;
+#[cfg(feature = "paranoid")]
+mod paranoid;
 mod parse;
+mod pipe;
+mod pipeline;
+mod profile;
+mod reduce;
 mod resolve;
+mod verify;
 
-use std::io::Read;
+use std::io::{self, Read, Write};
 
+pub use bytecode::{Bytecode, DebugInfo, Metadata};
+pub use config::{CellWidth, Compat, Config, Eof};
 use parse::{Jump, Op};
+pub use pipe::{pipe, Stats};
+pub use pipeline::{pipeline, Manifest, Node};
+pub use profile::{profile, Profile};
+pub use reduce::reduce;
+pub use verify::{verify, Divergence, Outcome};
 
-const RAM_SIZE: usize = 30_000;
 const DEFAULT_DEBUG_RANGE: usize = 5;
 
+/// Observes every instruction executed by the [`Cpu`].
+pub(crate) trait Probe {
+    fn step(&mut self, i: usize);
+}
+
+impl Probe for () {
+    #[inline(always)]
+    fn step(&mut self, _: usize) {}
+}
+
 #[derive(Debug)]
-pub struct Cpu {
+pub struct Cpu<R = io::Stdin, W = io::Stdout> {
     pc: usize,
-    ram: [u8; RAM_SIZE],
+    ram: Vec<u32>,
+    config: Config,
+    input: R,
+    output: W,
 }
 
 impl Default for Cpu {
     fn default() -> Self {
+        Self::new(Config::default())
+    }
+}
+
+impl Cpu {
+    pub fn new(config: Config) -> Self {
+        Self::with_io(config, io::stdin(), io::stdout())
+    }
+}
+
+impl<R: Read, W: Write> Cpu<R, W> {
+    pub fn with_io(config: Config, input: R, output: W) -> Self {
         Self {
             pc: 0,
-            ram: [0; RAM_SIZE],
+            ram: vec![0; config.tape_size],
+            config,
+            input,
+            output,
         }
     }
-}
 
-impl Cpu {
     pub fn reset(&mut self) {
-        *self = Self::default();
+        self.pc = 0;
+        self.ram.fill(0);
+    }
+
+    pub fn input(&self) -> &R {
+        &self.input
+    }
+
+    pub fn output(&self) -> &W {
+        &self.output
     }
 
+    /// Executes the instructions. Like a program receiving `SIGPIPE`, execution stops quietly as
+    /// soon as the reader of the output has exited, while any other error writing the output is
+    /// fatal.
     pub fn exec(&mut self, ops: Vec<Op>) {
+        self.exec_with(&ops, &mut ());
+    }
+
+    pub(crate) fn exec_with<P: Probe>(&mut self, ops: &[Op], probe: &mut P) {
+        let mask = self.config.cell_width.mask();
+        let tape_size = self.ram.len();
+        #[cfg(feature = "paranoid")]
+        paranoid::check_ops(ops);
         let mut i = 0;
         while i < ops.len() {
+            #[cfg(feature = "paranoid")]
+            assert!(
+                self.pc < tape_size,
+                "paranoid: pointer {} outside the tape before executing {i}",
+                self.pc
+            );
+            probe.step(i);
             match ops[i] {
                 Op::Increment(i) => {
-                    self.ram[self.pc] =
-                        self.ram[self.pc].wrapping_add((i % u8::MAX as usize) as u8);
+                    self.ram[self.pc] = self.ram[self.pc].wrapping_add(i as u32) & mask;
                 }
                 Op::Decrement(i) => {
-                    self.ram[self.pc] =
-                        self.ram[self.pc].wrapping_sub((i % u8::MAX as usize) as u8);
+                    self.ram[self.pc] = self.ram[self.pc].wrapping_sub(i as u32) & mask;
                 }
                 Op::MoveR(i) => {
                     self.pc += i;
-                    if self.pc >= RAM_SIZE {
-                        panic!("attempting to move past the last memory cell");
+                    if self.pc >= tape_size {
+                        if !self.config.wrap_tape {
+                            panic!("attempting to move past the last memory cell");
+                        }
+                        self.pc %= tape_size;
                     }
                 }
                 Op::MoveL(i) => {
-                    self.pc = self
-                        .pc
-                        .checked_sub(i)
-                        .expect("attempting to move behind the first memory cell");
+                    self.pc = match self.pc.checked_sub(i) {
+                        Some(pc) => pc,
+                        None if self.config.wrap_tape => {
+                            (self.pc + tape_size - i % tape_size) % tape_size
+                        }
+                        None => panic!("attempting to move behind the first memory cell"),
+                    };
                 }
                 Op::Jump(Jump::JumpR(r)) => {
                     if self.ram[self.pc] == 0 {
@@ -67,16 +139,27 @@ impl Cpu {
                 }
                 Op::Set => {
                     let mut buf = [0u8; 1];
-                    std::io::stdin()
-                        .read(&mut buf)
-                        .expect("failed to read input");
-                    self.ram[self.pc] = buf[0];
+                    let n = self.input.read(&mut buf).expect("failed to read input");
+                    if n > 0 {
+                        self.ram[self.pc] = buf[0] as u32;
+                    } else {
+                        match self.config.eof {
+                            Eof::Zero => self.ram[self.pc] = 0,
+                            Eof::MinusOne => self.ram[self.pc] = mask,
+                            Eof::Unchanged => {}
+                        }
+                    }
                 }
                 Op::Get => {
-                    print!("{}", self.ram[self.pc] as char);
+                    // Like `putchar`, only the low byte of the cell is written
+                    if output_closed(self.output.write_all(&[self.ram[self.pc] as u8])) {
+                        return;
+                    }
                 }
                 Op::Debug => {
-                    self.debug();
+                    if output_closed(self.debug()) {
+                        return;
+                    }
                 }
                 Op::Clear => {
                     self.ram[self.pc] = 0;
@@ -87,19 +170,22 @@ impl Cpu {
             }
             i += 1;
         }
+        output_closed(self.output.flush());
     }
 
     #[inline]
-    fn debug(&self) {
+    fn debug(&mut self) -> io::Result<()> {
         let debug_range = std::env::var("DEBUG_RANGE")
             .ok()
             .and_then(|r| r.parse().ok())
             .unwrap_or(DEFAULT_DEBUG_RANGE);
+        let tape_size = self.ram.len();
         let (start, end) = (
             self.pc.saturating_sub(debug_range),
-            (self.pc + debug_range + 1).min(RAM_SIZE),
+            (self.pc + debug_range + 1).min(tape_size),
         );
-        println!(
+        writeln!(
+            self.output,
             "MEM: [{}{} ({}) {}{}]",
             if start > 0 { "..." } else { "" },
             self.ram[start..self.pc]
@@ -108,21 +194,185 @@ impl Cpu {
                 .collect::<Vec<_>>()
                 .join(" "),
             self.ram[self.pc],
-            self.ram[(self.pc + 1).min(RAM_SIZE)..end]
+            self.ram[(self.pc + 1).min(tape_size)..end]
                 .iter()
                 .map(|v| v.to_string())
                 .collect::<Vec<_>>()
                 .join(" "),
-            if end < RAM_SIZE { "..." } else { "" },
-        );
+            if end < tape_size { "..." } else { "" },
+        )
     }
 }
 
-pub fn run(src: &str, cpu: &mut Cpu) {
-    let mut ops = parse::parse(src);
-    if std::env::var("NO_OPT") == Err(std::env::VarError::NotPresent) {
-        optimise::optimise(&mut ops);
+/// Checks the result of writing the output. Returns whether the reader of the output has exited,
+/// and panics on any other error.
+fn output_closed(result: io::Result<()>) -> bool {
+    match result {
+        Ok(()) => false,
+        Err(e) if e.kind() == io::ErrorKind::BrokenPipe => true,
+        Err(e) => panic!("failed to write output: {e}"),
     }
-    resolve::resolve_jumps(&mut ops);
+}
+
+pub fn run<R: Read, W: Write>(src: &str, cpu: &mut Cpu<R, W>) {
+    let (ops, _) = compile(src);
     cpu.exec(ops);
 }
+
+/// Parses and optimises the source, and resolves the jumps. Returns the instructions along with
+/// the byte offset of each instruction in the source.
+fn compile(src: &str) -> (Vec<Op>, Vec<usize>) {
+    let passes = if std::env::var("NO_OPT") == Err(std::env::VarError::NotPresent) {
+        optimise::PASSES
+    } else {
+        &[]
+    };
+    compile_with(src, passes)
+}
+
+fn compile_with(src: &str, passes: &[optimise::Pass]) -> (Vec<Op>, Vec<usize>) {
+    let (mut ops, mut offsets) = parse::parse(src);
+    optimise::optimise(passes, &mut ops, &mut offsets);
+    resolve::resolve_jumps(&mut ops);
+    (ops, offsets)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::{self, Write};
+
+    use super::{run, CellWidth, Compat, Config, Cpu};
+
+    /// A writer that fails every write with the given error.
+    struct Failing(io::ErrorKind);
+
+    impl Write for Failing {
+        fn write(&mut self, _: &[u8]) -> io::Result<usize> {
+            Err(self.0.into())
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Err(self.0.into())
+        }
+    }
+
+    fn run_compat<'a>(compat: Compat, src: &str, input: &'a [u8]) -> Cpu<&'a [u8], Vec<u8>> {
+        let mut cpu = Cpu::with_io(compat.config(), input, Vec::new());
+        run(src, &mut cpu);
+        cpu
+    }
+
+    /// Computes 256 in a cell, and runs `body` followed by `.` if the cell is not 0.
+    fn wide_cells(body: String) -> String {
+        format!(">++++++++[<++++++++>-]<[>++++<-]>[>{body}.<[-]]")
+    }
+
+    #[test]
+    fn compat_bff() {
+        let cpu = run_compat(Compat::Bff, "+,>-", b"");
+        assert_eq!(cpu.ram.len(), 65_536);
+        // EOF sets the cell to -1, and cells wrap at 8 bits
+        assert_eq!(cpu.ram[..2], [255, 255]);
+        // The tape wraps around at both ends
+        let cpu = run_compat(Compat::Bff, "<+<++", b"");
+        assert_eq!(cpu.ram[65_534..], [2, 1]);
+        // The usual `cat` for interpreters that set the cell to -1 on EOF
+        let cpu = run_compat(Compat::Bff, ",+[-.,+]", b"bff");
+        assert_eq!(cpu.output(), b"bff");
+        // Moving left from the first cell lands on the last cell
+        let cpu = run_compat(Compat::Bff, "<++++++++[>++++++++<-]>+.", b"");
+        assert_eq!(cpu.output(), b"A");
+    }
+
+    #[test]
+    fn compat_beef() {
+        let cpu = run_compat(Compat::Beef, "+++,>+++,>-", b"a");
+        assert_eq!(cpu.ram.len(), 30_000);
+        // EOF leaves the cell unchanged
+        assert_eq!(cpu.ram[..3], [b'a' as u32, 3, 255]);
+        // Reading at EOF keeps the `x` that was already in the cell
+        let cpu = run_compat(Compat::Beef, "++++++++++[>++++++++++++<-]>,.", b"");
+        assert_eq!(cpu.output(), b"x");
+    }
+
+    #[test]
+    #[should_panic(expected = "behind the first memory cell")]
+    fn compat_beef_no_wrap() {
+        run_compat(Compat::Beef, "<+", b"");
+    }
+
+    #[test]
+    fn compat_bfc() {
+        let cpu = run_compat(Compat::Bfc, "+++,>-", b"");
+        assert_eq!(cpu.ram.len(), 100_000);
+        assert_eq!(cpu.ram[..2], [3, 255]);
+        // Cells past the default tape of 30,000 cells are usable
+        let src = format!("{}{}.", ">".repeat(50_000), "+".repeat(65));
+        let cpu = run_compat(Compat::Bfc, &src, b"");
+        assert_eq!(cpu.output(), b"A");
+    }
+
+    #[test]
+    #[should_panic(expected = "past the last memory cell")]
+    fn compat_bfc_no_wrap() {
+        run_compat(Compat::Bfc, &">".repeat(100_000), b"");
+    }
+
+    #[test]
+    fn compat_tritium() {
+        let cpu = run_compat(Compat::Tritium, "+++,>-", b"");
+        assert_eq!(cpu.ram.len(), 1 << 20);
+        assert_eq!(cpu.ram[..2], [3, 255]);
+        // Cells are 8 bits wide, so 256 wraps around to 0 and the loop is skipped
+        let cpu = run_compat(Compat::Tritium, &wide_cells("+".repeat(65)), b"");
+        assert_eq!(cpu.output(), b"");
+        // Cells past the tapes of the other presets are usable
+        let src = format!("{}{}.", ">".repeat(500_000), "+".repeat(65));
+        let cpu = run_compat(Compat::Tritium, &src, b"");
+        assert_eq!(cpu.output(), b"A");
+    }
+
+    #[test]
+    fn compat_tritium_b32() {
+        // `tritium -b32`, where 256 does not wrap around to 0 and the loop is entered
+        let config = Config {
+            cell_width: CellWidth::U32,
+            ..Compat::Tritium.config()
+        };
+        let mut cpu = Cpu::with_io(config.clone(), &b""[..], Vec::new());
+        run(&wide_cells("+".repeat(65)), &mut cpu);
+        assert_eq!(cpu.output(), b"A");
+        // Only the low byte of the cell is written, so 256 + 65 is written as `A`
+        let mut cpu = Cpu::with_io(config, &b""[..], Vec::new());
+        run(&format!("{}.", "+".repeat(256 + 65)), &mut cpu);
+        assert_eq!(cpu.output(), b"A");
+    }
+
+    #[test]
+    fn output_closed() {
+        // The program would never stop by itself
+        let mut cpu = Cpu::with_io(
+            Config::default(),
+            &b""[..],
+            Failing(io::ErrorKind::BrokenPipe),
+        );
+        run("+[.]", &mut cpu);
+    }
+
+    #[test]
+    #[should_panic(expected = "failed to write output")]
+    fn output_failed() {
+        let mut cpu = Cpu::with_io(
+            Config::default(),
+            &b""[..],
+            Failing(io::ErrorKind::StorageFull),
+        );
+        run("+.", &mut cpu);
+    }
+
+    #[test]
+    fn output_low_byte() {
+        let cpu = run_compat(Compat::Bff, "-.", b"");
+        assert_eq!(cpu.output(), &[0xff]);
+    }
+}
@@ -1,20 +1,87 @@
 use std::{
-    env,
-    io::{self, Write},
-    path::Path,
+    env, fs,
+    io::{self, Read, Write},
+    panic,
+    path::{Path, PathBuf},
+    process::{self, Command, Stdio},
+    thread,
+    time::{Duration, Instant},
 };
 
-use bri::{run, Cpu};
+use bri::{
+    pipe, pipeline, profile, reduce, run, verify, Bytecode, Compat, Config, Cpu, Manifest, Stats,
+};
+
+#[derive(Default)]
+struct Options {
+    config: Config,
+    /// The compatibility preset, if any, to forward to other invocations of the interpreter
+    compat: Option<String>,
+    /// The sampling period for the profiler, if enabled
+    sample: Option<usize>,
+    /// The path to write the folded stacks from the profiler to, if enabled
+    flamegraph: Option<String>,
+    /// Whether to compare the optimised and unoptimised runs instead of running the program
+    verify: bool,
+    /// Whether to find the first diverging optimisation pass when verifying
+    bisect_passes: bool,
+    /// The number of instructions after which each run is stopped when verifying
+    step_limit: Option<u64>,
+}
 
 fn main() {
-    let args: Vec<String> = env::args().skip(1).collect();
-    match args.len() {
-        0 => run_repl(),
-        1 => run_file(&args[0]),
-        _ => {
+    let mut args = env::args().skip(1);
+    let mut opts = Options::default();
+    let mut files = vec![];
+    while let Some(arg) = args.next() {
+        let mut value = || {
+            args.next()
+                .unwrap_or_else(|| fail(&format!("missing value for `{arg}`")))
+        };
+        match arg.as_str() {
+            "--compat" => {
+                let preset = value();
+                opts.config = preset
+                    .parse::<Compat>()
+                    .unwrap_or_else(|e: String| fail(&e))
+                    .config();
+                opts.compat = Some(preset);
+            }
+            "--sample" => {
+                opts.sample = match value().parse() {
+                    Ok(0) | Err(_) => fail("`--sample` must be a positive number of instructions"),
+                    Ok(n) => Some(n),
+                }
+            }
+            "--flamegraph" => opts.flamegraph = Some(value()),
+            "--verify" => opts.verify = true,
+            "--bisect-passes" => (opts.verify, opts.bisect_passes) = (true, true),
+            "--step-limit" => {
+                opts.step_limit = match value().parse() {
+                    Ok(0) | Err(_) => {
+                        fail("`--step-limit` must be a positive number of instructions")
+                    }
+                    Ok(n) => Some(n),
+                }
+            }
+            _ => files.push(arg),
+        }
+    }
+    if opts.flamegraph.is_some() && opts.sample.is_none() {
+        opts.sample = Some(DEFAULT_SAMPLE_PERIOD);
+    }
+    match files.first().map(String::as_str) {
+        Some("pipe") => run_pipe(&files[1..], &opts.config),
+        Some("pipeline") => run_pipeline(&files[1..], &opts.config),
+        Some("reduce") => run_reduce(&files[1..], opts.compat.as_deref()),
+        Some("compile") => run_compile(&files[1..], &opts),
+        Some("inspect") => run_inspect(&files[1..]),
+        None => run_repl(opts.config),
+        Some(_) if files.len() == 1 => run_file(&files[0], &opts),
+        Some(_) => {
             eprintln!("Multiple input files provided, they will be run in the provided order");
-            for arg in &args {
-                run_file(arg);
+            for file in &files {
+                run_file(file, &opts);
             }
         }
     }
@@ -22,8 +89,16 @@ fn main() {
 
 const VERSION: &str = env!("CARGO_PKG_VERSION");
 const AUTHORS: &str = env!("CARGO_PKG_AUTHORS");
+const DEFAULT_SAMPLE_PERIOD: usize = 1000;
+const DEFAULT_REDUCE_TIMEOUT: Duration = Duration::from_secs(2);
+const DEFAULT_STEP_LIMIT: u64 = 10_000_000_000;
+
+fn fail(msg: &str) -> ! {
+    eprintln!("error: {msg}");
+    process::exit(1);
+}
 
-fn run_repl() {
+fn run_repl(config: Config) {
     println!(
         "Brainrot REPL v{} on {} ({}), Copyright (c) {}",
         VERSION,
@@ -32,7 +107,7 @@ fn run_repl() {
         AUTHORS
     );
     let (stdin, mut stdout) = (io::stdin(), io::stdout());
-    let mut cpu = Cpu::default();
+    let mut cpu = Cpu::new(config);
     loop {
         let mut line = String::default();
         print!(">>> ");
@@ -47,11 +122,279 @@ fn run_repl() {
             continue;
         }
         run(&line, &mut cpu);
-        print!("\n");
+        println!();
+    }
+}
+
+fn run_file(path: impl AsRef<Path>, opts: &Options) {
+    let bytes = fs::read(path).expect("failed to read program");
+    if Bytecode::is_bytecode(&bytes) {
+        if opts.verify || opts.sample.is_some() {
+            fail("compiled programs cannot be verified or profiled, use the source instead");
+        }
+        let bytecode = Bytecode::decode(&bytes).unwrap_or_else(|e: String| fail(&e));
+        return bytecode.exec(&mut Cpu::new(bytecode.metadata.config.clone()));
     }
+    let src = String::from_utf8(bytes).expect("failed to read program");
+    if opts.verify {
+        return verify_file(&src, opts);
+    }
+    let mut cpu = Cpu::new(opts.config.clone());
+    let Some(period) = opts.sample else {
+        return run(&src, &mut cpu);
+    };
+    let profile = profile(&src, &mut cpu, period);
+    eprint!("\n{profile}");
+    if let Some(path) = &opts.flamegraph {
+        fs::write(path, profile.folded()).expect("failed to write flamegraph");
+    }
+}
+
+fn run_compile(args: &[String], opts: &Options) {
+    let (mut path, mut output, mut author, mut comment, mut strip) =
+        (None, None, None, None, false);
+    let mut args = args.iter();
+    while let Some(arg) = args.next() {
+        let mut value = || {
+            args.next()
+                .unwrap_or_else(|| fail(&format!("missing value for `{arg}`")))
+        };
+        match arg.as_str() {
+            "-o" | "--output" => output = Some(value()),
+            "--author" => author = Some(value()),
+            "--comment" => comment = Some(value()),
+            "--strip" => strip = true,
+            _ if path.is_none() => path = Some(arg),
+            _ => fail("expected a single program for `compile`"),
+        }
+    }
+    let path = path.unwrap_or_else(|| fail("expected a program for `compile`"));
+    let src = fs::read_to_string(path).expect("failed to read program");
+    let mut bytecode = Bytecode::new(
+        &src,
+        opts.config.clone(),
+        opts.compat.as_deref().unwrap_or("default"),
+        (!strip).then_some(path.as_str()),
+    );
+    bytecode.metadata.author = author.cloned();
+    bytecode.metadata.comment = comment.cloned();
+    let output = output.map_or_else(|| Path::new(path).with_extension("brc"), PathBuf::from);
+    fs::write(output, bytecode.encode()).expect("failed to write compiled program");
 }
 
-fn run_file(path: impl AsRef<Path>) {
-    let src = std::fs::read_to_string(path).expect("failed to read program");
-    run(&src, &mut Cpu::default());
+fn run_inspect(args: &[String]) {
+    let [path] = args else {
+        fail("expected a single compiled program for `inspect`");
+    };
+    let bytes = fs::read(path).expect("failed to read compiled program");
+    let bytecode = Bytecode::decode(&bytes).unwrap_or_else(|e: String| fail(&e));
+    let version = Bytecode::version(&bytes).expect("bytecode has already been decoded");
+    println!("Version:      {version}");
+    print!("{}", bytecode.metadata);
+    println!("Instructions: {}", bytecode.len());
+}
+
+fn verify_file(src: &str, opts: &Options) {
+    // Both runs must read the same input, so it is read upfront
+    let mut input = vec![];
+    io::stdin()
+        .read_to_end(&mut input)
+        .expect("failed to read input");
+    // Panics are a part of the outcome, so the default hook should not report them
+    let hook = panic::take_hook();
+    panic::set_hook(Box::new(|_| {}));
+    let limit = opts.step_limit.unwrap_or(DEFAULT_STEP_LIMIT);
+    let divergence = verify(src, &opts.config, &input, limit, opts.bisect_passes);
+    panic::set_hook(hook);
+    let divergence =
+        divergence.unwrap_or_else(|e| fail(&format!("{e}, try a larger `--step-limit`")));
+    match divergence {
+        Some(divergence) => {
+            eprint!("{divergence}");
+            process::exit(1);
+        }
+        None => eprintln!("No divergence between the optimised and unoptimised runs"),
+    }
+}
+
+fn run_pipe(paths: &[String], config: &Config) {
+    if paths.len() < 2 {
+        fail("at least two programs are required for `pipe`");
+    }
+    let srcs: Vec<_> = paths
+        .iter()
+        .map(|p| std::fs::read_to_string(p).expect("failed to read program"))
+        .collect();
+    let start = Instant::now();
+    let stats = pipe(&srcs, config, io::stdin(), io::stdout());
+    let elapsed = start.elapsed();
+    print_stats(paths.iter().zip(&stats));
+    let (read, written) = (
+        stats.first().and_then(Option::as_ref).map_or(0, |s| s.read),
+        stats
+            .last()
+            .and_then(Option::as_ref)
+            .map_or(0, |s| s.written),
+    );
+    eprintln!(
+        "pipe: read {read} bytes, wrote {written} bytes in {:.4}s",
+        elapsed.as_secs_f64()
+    );
+    if stats.iter().any(Option::is_none) {
+        process::exit(1);
+    }
+}
+
+fn run_pipeline(args: &[String], config: &Config) {
+    let [path] = args else {
+        fail("expected a single manifest for `pipeline`");
+    };
+    let path = Path::new(path);
+    let manifest: Manifest = std::fs::read_to_string(path)
+        .expect("failed to read manifest")
+        .parse()
+        .unwrap_or_else(|e: String| fail(&e));
+    let dir = path.parent().unwrap_or(Path::new("."));
+    let start = Instant::now();
+    let stats = pipeline(&manifest, dir, config).unwrap_or_else(|e: String| fail(&e));
+    let elapsed = start.elapsed();
+    print_stats(stats.iter().map(|(name, s)| (name, s)));
+    let (read, written) = stats
+        .iter()
+        .filter_map(|(_, s)| s.as_ref())
+        .fold((0, 0), |(r, w), s| (r + s.read, w + s.written));
+    eprintln!(
+        "pipeline: {} programs read {read} bytes, wrote {written} bytes in {:.4}s",
+        stats.len(),
+        elapsed.as_secs_f64()
+    );
+    if stats.iter().any(|(_, s)| s.is_none()) {
+        process::exit(1);
+    }
+}
+
+fn print_stats<'a>(stats: impl Iterator<Item = (&'a String, &'a Option<Stats>)>) {
+    eprintln!();
+    for (name, stats) in stats {
+        match stats {
+            Some(s) => eprintln!(
+                "{name}: read {} bytes, wrote {} bytes in {:.4}s",
+                s.read,
+                s.written,
+                s.elapsed.as_secs_f64()
+            ),
+            None => eprintln!("{name}: failed"),
+        }
+    }
+}
+
+fn run_reduce(args: &[String], compat: Option<&str>) {
+    let (mut path, mut oracle, mut input, mut timeout) = (None, None, None, DEFAULT_REDUCE_TIMEOUT);
+    let mut args = args.iter();
+    while let Some(arg) = args.next() {
+        let mut value = || {
+            args.next()
+                .unwrap_or_else(|| fail(&format!("missing value for `{arg}`")))
+        };
+        match arg.as_str() {
+            "--oracle" => oracle = Some(value()),
+            "--input" => input = Some(value()),
+            "--timeout" => {
+                timeout = value()
+                    .parse()
+                    .ok()
+                    .and_then(|secs| Duration::try_from_secs_f64(secs).ok())
+                    .unwrap_or_else(|| fail("`--timeout` must be a non-negative number of seconds"))
+            }
+            _ if path.is_none() => path = Some(arg),
+            _ => fail("expected a single program for `reduce`"),
+        }
+    }
+    let path = path.unwrap_or_else(|| fail("expected a program for `reduce`"));
+    let src = fs::read_to_string(path).expect("failed to read program");
+
+    let candidate = env::temp_dir().join(format!("bri-reduce-{}.b", process::id()));
+    let mut tests = 0;
+    let mut interesting = |src: &str| {
+        tests += 1;
+        fs::write(&candidate, src).expect("failed to write candidate program");
+        match oracle {
+            Some(oracle) => {
+                let mut cmd = Command::new("sh");
+                cmd.arg("-c")
+                    .arg(oracle.replace("{}", &candidate.to_string_lossy()))
+                    .stdin(Stdio::null())
+                    .stdout(Stdio::null());
+                run_with_timeout(cmd, timeout).is_some_and(|(success, _)| success)
+            }
+            None => diverges(&candidate, input.map(String::as_str), compat, timeout),
+        }
+    };
+    if !interesting(&src) {
+        fail("the program is not interesting to the oracle");
+    }
+    let reduced = reduce(&src, &mut interesting);
+    fs::remove_file(&candidate).ok();
+    eprintln!(
+        "Reduced from {} to {} characters with {tests} tests",
+        src.chars().count(),
+        reduced.chars().count()
+    );
+    println!("{reduced}");
+}
+
+/// Checks whether the optimised run of a program diverges from the unoptimised run, which must
+/// succeed. Runs that time out are never considered to diverge.
+fn diverges(path: &Path, input: Option<&str>, compat: Option<&str>, timeout: Duration) -> bool {
+    let run = |optimise: bool| {
+        let mut cmd = Command::new(env::current_exe().expect("failed to find the interpreter"));
+        if let Some(compat) = compat {
+            cmd.args(["--compat", compat]);
+        }
+        if optimise {
+            cmd.env_remove("NO_OPT");
+        } else {
+            cmd.env("NO_OPT", "1");
+        }
+        cmd.arg(path).stdout(Stdio::piped()).stdin(match input {
+            Some(input) => fs::File::open(input).expect("failed to open input").into(),
+            None => Stdio::null(),
+        });
+        run_with_timeout(cmd, timeout)
+    };
+    match (run(false), run(true)) {
+        (Some(expected @ (true, _)), Some(actual)) => expected != actual,
+        _ => false,
+    }
+}
+
+/// Runs a command, and returns whether it succeeded along with its stdout (if piped), or `None`
+/// if it was killed for running longer than the timeout.
+fn run_with_timeout(mut cmd: Command, timeout: Duration) -> Option<(bool, Vec<u8>)> {
+    let mut child = cmd
+        .stderr(Stdio::null())
+        .spawn()
+        .expect("failed to run command");
+    // Drain stdout while waiting, to avoid blocking the child on a full pipe
+    let stdout = child.stdout.take().map(|mut stdout| {
+        thread::spawn(move || {
+            let mut buf = vec![];
+            stdout.read_to_end(&mut buf).ok();
+            buf
+        })
+    });
+    let deadline = Instant::now() + timeout;
+    let status = loop {
+        if let Some(status) = child.try_wait().expect("failed to wait for command") {
+            break Some(status);
+        }
+        if Instant::now() >= deadline {
+            child.kill().ok();
+            child.wait().ok();
+            break None;
+        }
+        thread::sleep(Duration::from_millis(5));
+    };
+    let stdout = stdout.map_or_else(Vec::new, |h| h.join().unwrap_or_default());
+    status.map(|s| (s.success(), stdout))
 }
@@ -2,13 +2,43 @@ use std::cmp::Ordering;
 
 use crate::parse::{Jump, Op};
 
-pub fn optimise(ops: &mut Vec<Op>) {
-    fold_consecutive_ops(Op::MoveL, Op::MoveR, ops);
-    fold_consecutive_ops(Op::Decrement, Op::Increment, ops);
-    rewrite_clear_loops(ops);
-    remove_dead_loops(ops);
-    remove_trailing_ops(ops);
-    remove_empty_ops(ops);
+/// An optimisation pass, which replaces the instructions it removes with `Op::Empty`.
+pub struct Pass {
+    pub name: &'static str,
+    pub run: fn(&mut [Op]),
+}
+
+/// The optimisation passes, in the order that they are run.
+pub const PASSES: &[Pass] = &[
+    Pass {
+        name: "fold_moves",
+        run: |ops| fold_consecutive_ops(Op::MoveL, Op::MoveR, ops),
+    },
+    Pass {
+        name: "fold_arithmetic",
+        run: |ops| fold_consecutive_ops(Op::Decrement, Op::Increment, ops),
+    },
+    Pass {
+        name: "rewrite_clear_loops",
+        run: rewrite_clear_loops,
+    },
+    Pass {
+        name: "remove_dead_loops",
+        run: remove_dead_loops,
+    },
+    Pass {
+        name: "remove_trailing_ops",
+        run: remove_trailing_ops,
+    },
+];
+
+/// Optimises the instructions in place with the given passes. The offsets of the instructions in
+/// the source are kept aligned with the remaining instructions.
+pub fn optimise(passes: &[Pass], ops: &mut Vec<Op>, offsets: &mut Vec<usize>) {
+    for pass in passes {
+        (pass.run)(ops);
+    }
+    remove_empty_ops(ops, offsets);
 }
 
 /// A pair of operations that move in opposite directions when visualised in a 2D
@@ -41,7 +71,7 @@ where
             }
 
             ops[start] = match net.cmp(&0) {
-                Ordering::Less => left(net.abs() as usize),
+                Ordering::Less => left(net.unsigned_abs()),
                 Ordering::Greater => right(net as usize),
                 Ordering::Equal => Op::Empty,
             };
@@ -82,7 +112,7 @@ fn rewrite_clear_loops(ops: &mut [Op]) {
 /// A loop at the beginning of the program is dead.
 /// A loop immediately after another loop is dead.
 fn remove_dead_loops(ops: &mut [Op]) {
-    if matches!(ops.get(0), Some(&Op::Jump(Jump::JumpR(_)))) {
+    if matches!(ops.first(), Some(&Op::Jump(Jump::JumpR(_)))) {
         let n = ops
             .iter()
             .take_while(|op| !matches!(**op, Op::Jump(Jump::JumpL(_))))
@@ -141,7 +171,9 @@ fn remove_trailing_ops(ops: &mut [Op]) {
     ops[end + 1..].fill(Op::Empty);
 }
 
-fn remove_empty_ops(ops: &mut Vec<Op>) {
+fn remove_empty_ops(ops: &mut Vec<Op>, offsets: &mut Vec<usize>) {
+    let mut keep = ops.iter().map(|op| *op != Op::Empty);
+    offsets.retain(|_| keep.next().unwrap_or_default());
     ops.retain(|op| *op != Op::Empty);
 }
 
@@ -259,8 +291,16 @@ mod tests {
     #[test]
     fn remove_empty_ops() {
         let mut ops = vec![Op::Empty, Op::Empty, Op::Empty, Op::Empty];
-        super::remove_empty_ops(&mut ops);
+        let mut offsets = vec![0, 1, 2, 3];
+        super::remove_empty_ops(&mut ops, &mut offsets);
         assert_eq!(ops, []);
+        assert_eq!(offsets, []);
+
+        let mut ops = vec![Op::Increment(2), Op::Empty, Op::Get, Op::Empty];
+        let mut offsets = vec![0, 1, 3, 4];
+        super::remove_empty_ops(&mut ops, &mut offsets);
+        assert_eq!(ops, [Op::Increment(2), Op::Get]);
+        assert_eq!(offsets, [0, 3]);
     }
 
     #[test]
@@ -0,0 +1,65 @@
+//! Heavyweight invariant checks for chasing miscompilations, enabled with the `paranoid` feature.
+
+use crate::parse::{Jump, Op};
+
+/// Checks that a program is fit for execution after optimisation and jump resolution:
+///
+/// - every jump targets the instruction right after its matching jump
+/// - folded instructions have a non-zero count
+/// - no empty instructions are left behind
+pub fn check_ops(ops: &[Op]) {
+    for (i, op) in ops.iter().enumerate() {
+        match *op {
+            Op::Increment(0) | Op::Decrement(0) | Op::MoveR(0) | Op::MoveL(0) => {
+                panic!("paranoid: unnormalised fold `{op:?}` at {i}");
+            }
+            Op::Empty => panic!("paranoid: empty instruction at {i}"),
+            Op::Jump(Jump::JumpR(r)) => {
+                assert!(
+                    r > i
+                        && matches!(ops.get(r - 1), Some(Op::Jump(Jump::JumpL(l))) if *l == i + 1),
+                    "paranoid: `[` at {i} does not jump past its matching `]` (target {r})"
+                );
+            }
+            Op::Jump(Jump::JumpL(l)) => {
+                assert!(
+                    l > 0 && l <= i && matches!(ops[l - 1], Op::Jump(Jump::JumpR(r)) if r == i + 1),
+                    "paranoid: `]` at {i} does not jump past its matching `[` (target {l})"
+                );
+            }
+            _ => {}
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::parse::{Jump, Op};
+
+    #[test]
+    fn valid() {
+        super::check_ops(&[
+            Op::Increment(1),
+            Op::Jump(Jump::JumpR(4)),
+            Op::MoveR(2),
+            Op::Jump(Jump::JumpL(2)),
+            Op::Get,
+        ]);
+    }
+
+    #[test]
+    #[should_panic(expected = "does not jump past its matching `]`")]
+    fn invalid_jump() {
+        super::check_ops(&[
+            Op::Jump(Jump::JumpR(1)),
+            Op::Decrement(1),
+            Op::Jump(Jump::JumpL(1)),
+        ]);
+    }
+
+    #[test]
+    #[should_panic(expected = "unnormalised fold")]
+    fn unnormalised_fold() {
+        super::check_ops(&[Op::MoveL(0)]);
+    }
+}
@@ -1,3 +1,5 @@
+use std::fmt;
+
 #[derive(Clone, Debug, PartialEq, Eq)]
 pub enum Op {
     Increment(usize),
@@ -33,14 +35,44 @@ impl TryFrom<char> for Op {
     }
 }
 
+impl fmt::Display for Op {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Increment(i) => write!(f, "+{i}"),
+            Self::Decrement(i) => write!(f, "-{i}"),
+            Self::MoveR(i) => write!(f, ">{i}"),
+            Self::MoveL(i) => write!(f, "<{i}"),
+            Self::Jump(Jump::JumpR(_)) => write!(f, "["),
+            Self::Jump(Jump::JumpL(_)) => write!(f, "]"),
+            Self::Set => write!(f, ","),
+            Self::Get => write!(f, "."),
+            Self::Debug => write!(f, "#"),
+            Self::Clear => write!(f, "clear"),
+            Self::Empty => write!(f, "empty"),
+        }
+    }
+}
+
 #[derive(Clone, Debug, PartialEq, Eq)]
 pub enum Jump {
     JumpR(usize),
     JumpL(usize),
 }
 
-pub fn parse(src: &str) -> Vec<Op> {
-    src.chars().flat_map(Op::try_from).collect()
+/// Parses the source into instructions, along with the byte offset of each instruction in the
+/// source.
+pub fn parse(src: &str) -> (Vec<Op>, Vec<usize>) {
+    src.char_indices()
+        .filter_map(|(i, c)| Op::try_from(c).ok().map(|op| (op, i)))
+        .unzip()
+}
+
+/// Line and column (both starting at 1) of a byte offset in the source.
+pub fn location(src: &str, offset: usize) -> (usize, usize) {
+    let before = &src[..offset];
+    let line = before.matches('\n').count() + 1;
+    let col = before.rsplit('\n').next().map_or(0, |l| l.chars().count()) + 1;
+    (line, col)
 }
 
 #[cfg(test)]
@@ -51,17 +83,21 @@ mod tests {
     fn trivial() {
         assert_eq!(
             super::parse("+-><[],.#"),
-            vec![
-                Op::Increment(1),
-                Op::Decrement(1),
-                Op::MoveR(1),
-                Op::MoveL(1),
-                Op::Jump(Jump::JumpR(0)),
-                Op::Jump(Jump::JumpL(0)),
-                Op::Set,
-                Op::Get,
-                Op::Debug,
-            ]
-        )
+            (
+                vec![
+                    Op::Increment(1),
+                    Op::Decrement(1),
+                    Op::MoveR(1),
+                    Op::MoveL(1),
+                    Op::Jump(Jump::JumpR(0)),
+                    Op::Jump(Jump::JumpL(0)),
+                    Op::Set,
+                    Op::Get,
+                    Op::Debug,
+                ],
+                vec![0, 1, 2, 3, 4, 5, 6, 7, 8]
+            )
+        );
+        assert_eq!(super::parse("+ x\n-").1, [0, 4]);
     }
 }
@@ -0,0 +1,259 @@
+use std::{
+    io::{self, Read, Write},
+    iter, mem,
+    sync::mpsc::{self, Receiver, SyncSender},
+    thread,
+    time::{Duration, Instant},
+};
+
+use crate::{run, Config, Cpu};
+
+/// Output is sent across the channel in chunks of at most this many bytes.
+const CHUNK_SIZE: usize = 4096;
+/// Number of chunks that can be in flight before a writer blocks on its reader.
+const CHANNEL_BOUND: usize = 16;
+
+/// Statistics for a single program in a pipeline.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct Stats {
+    pub read: usize,
+    pub written: usize,
+    pub elapsed: Duration,
+}
+
+/// Runs the programs in order, each on its own thread and [`Cpu`], with the output of every
+/// program connected to the input of the next one. The first program reads from `input`, and the
+/// last program writes to `output`.
+///
+/// Returns the statistics for each program, or `None` if it failed.
+pub fn pipe<R, W>(srcs: &[String], config: &Config, input: R, output: W) -> Vec<Option<Stats>>
+where
+    R: Read + Send,
+    W: Write + Send,
+{
+    let (writers, readers): (Vec<_>, Vec<_>) = (1..srcs.len()).map(|_| bounded_channel()).unzip();
+    let inputs = iter::once(Box::new(input) as Box<dyn Read + Send>).chain(
+        readers
+            .into_iter()
+            .map(|r| Box::new(r) as Box<dyn Read + Send>),
+    );
+    let outputs = writers
+        .into_iter()
+        .map(|w| Box::new(w) as Box<dyn Write + Send>)
+        .chain(iter::once(Box::new(output) as Box<dyn Write + Send>));
+
+    thread::scope(|s| {
+        let handles: Vec<_> = srcs
+            .iter()
+            .zip(inputs.zip(outputs))
+            .map(|(src, (input, output))| s.spawn(move || run_counted(src, config, input, output)))
+            .collect();
+        handles.into_iter().map(|h| h.join().ok()).collect()
+    })
+}
+
+/// Runs a single program of a pipe or pipeline on its own [`Cpu`], counting the bytes that it
+/// reads and writes.
+pub(crate) fn run_counted(
+    src: &str,
+    config: &Config,
+    input: impl Read,
+    output: impl Write,
+) -> Stats {
+    let start = Instant::now();
+    let mut cpu = Cpu::with_io(config.clone(), Counted::new(input), Counted::new(output));
+    run(src, &mut cpu);
+    Stats {
+        read: cpu.input().count,
+        written: cpu.output().count,
+        elapsed: start.elapsed(),
+    }
+}
+
+pub(crate) fn bounded_channel() -> (ChannelWriter, ChannelReader) {
+    let (tx, rx) = mpsc::sync_channel(CHANNEL_BOUND);
+    channel(Sender::Bounded(tx), rx)
+}
+
+pub(crate) fn unbounded_channel() -> (ChannelWriter, ChannelReader) {
+    let (tx, rx) = mpsc::channel();
+    channel(Sender::Unbounded(tx), rx)
+}
+
+fn channel(tx: Sender, rx: Receiver<Vec<u8>>) -> (ChannelWriter, ChannelReader) {
+    (
+        ChannelWriter {
+            tx,
+            buf: Vec::with_capacity(CHUNK_SIZE),
+        },
+        ChannelReader {
+            rx,
+            chunk: Vec::default(),
+            pos: 0,
+        },
+    )
+}
+
+enum Sender {
+    Bounded(SyncSender<Vec<u8>>),
+    Unbounded(mpsc::Sender<Vec<u8>>),
+}
+
+/// The sending half of a pipe between two programs. Output is buffered into chunks, and blocks
+/// when the receiving program falls behind if the pipe is bounded.
+pub(crate) struct ChannelWriter {
+    tx: Sender,
+    buf: Vec<u8>,
+}
+
+impl Write for ChannelWriter {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.buf.extend_from_slice(buf);
+        if self.buf.len() >= CHUNK_SIZE {
+            self.flush()?;
+        }
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        if self.buf.is_empty() {
+            return Ok(());
+        }
+        let chunk = mem::replace(&mut self.buf, Vec::with_capacity(CHUNK_SIZE));
+        let sent = match &self.tx {
+            Sender::Bounded(tx) => tx.send(chunk),
+            Sender::Unbounded(tx) => tx.send(chunk),
+        };
+        sent.map_err(|_| io::Error::from(io::ErrorKind::BrokenPipe))
+    }
+}
+
+impl Drop for ChannelWriter {
+    fn drop(&mut self) {
+        // The receiving program may have already exited, in which case the output is discarded
+        let _ = self.flush();
+    }
+}
+
+/// The receiving half of a pipe between two programs. Reaches EOF once the sending program has
+/// exited and all of its output has been read.
+pub(crate) struct ChannelReader {
+    rx: Receiver<Vec<u8>>,
+    chunk: Vec<u8>,
+    pos: usize,
+}
+
+impl Read for ChannelReader {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        while self.pos == self.chunk.len() {
+            match self.rx.recv() {
+                Ok(chunk) => (self.chunk, self.pos) = (chunk, 0),
+                Err(_) => return Ok(0),
+            }
+        }
+        let n = buf.len().min(self.chunk.len() - self.pos);
+        buf[..n].copy_from_slice(&self.chunk[self.pos..self.pos + n]);
+        self.pos += n;
+        Ok(n)
+    }
+}
+
+/// Counts the number of bytes passing through a reader or writer.
+pub(crate) struct Counted<T> {
+    inner: T,
+    pub(crate) count: usize,
+}
+
+impl<T> Counted<T> {
+    pub(crate) fn new(inner: T) -> Self {
+        Self { inner, count: 0 }
+    }
+}
+
+impl<T: Read> Read for Counted<T> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let n = self.inner.read(buf)?;
+        self.count += n;
+        Ok(n)
+    }
+}
+
+impl<T: Write> Write for Counted<T> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let n = self.inner.write(buf)?;
+        self.count += n;
+        Ok(n)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.inner.flush()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::Config;
+
+    #[test]
+    fn two_programs() {
+        let mut out = Vec::new();
+        let stats = super::pipe(
+            &["++++++++[>++++++++<-]>+.+.+.".into(), ",[+.,]".into()],
+            &Config::default(),
+            &b""[..],
+            &mut out,
+        );
+        assert_eq!(out, b"BCD");
+        let stats: Vec<_> = stats
+            .into_iter()
+            .map(|s| s.map(|s| (s.read, s.written)))
+            .collect();
+        assert_eq!(stats, [Some((0, 3)), Some((3, 3))]);
+    }
+
+    #[test]
+    fn large_output() {
+        let input: Vec<u8> = (0..20_000).map(|i| b'a' + (i % 26) as u8).collect();
+        let mut out = Vec::new();
+        let stats = super::pipe(
+            &[",[.,]".into(), ",[.,]".into(), ",[.,]".into()],
+            &Config::default(),
+            &input[..],
+            &mut out,
+        );
+        assert_eq!(out, input);
+        assert!(stats.iter().all(Option::is_some));
+    }
+
+    #[test]
+    fn binary() {
+        // Bytes outside of ASCII must pass through every program unchanged
+        let input = [0xe9, 0x00, 0x80, 0xff];
+        let mut out = Vec::new();
+        let stats = super::pipe(
+            &[",.,.,.,.".into(), ",.,.,.,.".into()],
+            &Config::default(),
+            &input[..],
+            &mut out,
+        );
+        assert_eq!(out, input);
+        assert!(stats
+            .iter()
+            .all(|s| s.as_ref().is_some_and(|s| s.written == 4)));
+    }
+
+    #[test]
+    fn early_exit() {
+        // The generator never stops by itself, so it must stop once the filter has exited
+        let mut out = Vec::new();
+        let stats = super::pipe(
+            &["+[.]".into(), ",.".into()],
+            &Config::default(),
+            &b""[..],
+            &mut out,
+        );
+        assert_eq!(out, [1]);
+        assert!(stats[0].as_ref().is_some_and(|s| s.written > 0));
+        assert_eq!(stats[1].as_ref().map(|s| (s.read, s.written)), Some((1, 1)));
+    }
+}
@@ -0,0 +1,530 @@
+//! Pipelines of programs connected as a directed acyclic graph, described by a manifest in a
+//! small subset of TOML:
+//!
+//! ```toml
+//! # Every table is a program, identified by the table name
+//! [numbers]
+//! program = "numbers.b"
+//! # Reads the input from a file, relative to the manifest
+//! input = "seed.txt"
+//!
+//! [evens]
+//! program = "evens.b"
+//! inputs = ["numbers"]
+//!
+//! [odds]
+//! program = "odds.b"
+//! inputs = ["numbers"]
+//!
+//! [merge]
+//! program = "merge.b"
+//! # The outputs of multiple programs are concatenated in the listed order
+//! inputs = ["evens", "odds"]
+//! # Writes the output to a file, relative to the manifest
+//! output = "merged.txt"
+//! ```
+//!
+//! A program without an `input` or `inputs` receives no input. The output of a program is written
+//! to stdout if neither `output` is set nor is it the input of another program.
+
+use std::{
+    collections::{HashMap, VecDeque},
+    fs::{self, File},
+    io::{self, Read, Write},
+    path::{Path, PathBuf},
+    str::FromStr,
+    thread,
+};
+
+use crate::{
+    pipe::{bounded_channel, run_counted, unbounded_channel},
+    Config, Stats,
+};
+
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct Node {
+    pub name: String,
+    pub program: PathBuf,
+    pub input: Option<PathBuf>,
+    pub inputs: Vec<String>,
+    pub output: Option<PathBuf>,
+}
+
+/// A validated pipeline, with the nodes in topological order.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct Manifest {
+    pub nodes: Vec<Node>,
+}
+
+enum Value {
+    Str(String),
+    List(Vec<String>),
+}
+
+impl FromStr for Manifest {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let mut nodes: Vec<Node> = vec![];
+        let mut keys: Vec<String> = vec![];
+        for (n, line) in s.lines().enumerate().map(|(n, l)| (n + 1, l.trim())) {
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+            if let Some(header) = line.strip_prefix('[') {
+                let (name, rest) = header
+                    .split_once(']')
+                    .ok_or(format!("line {n}: unterminated table header"))?;
+                let name = name.trim();
+                if !is_comment(rest) {
+                    return Err(format!(
+                        "line {n}: unexpected characters after table header"
+                    ));
+                }
+                if name.is_empty()
+                    || !name
+                        .chars()
+                        .all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_')
+                {
+                    return Err(format!("line {n}: invalid program name `{name}`"));
+                }
+                if nodes.iter().any(|node| node.name == name) {
+                    return Err(format!("line {n}: duplicate program `{name}`"));
+                }
+                nodes.push(Node {
+                    name: name.into(),
+                    ..Default::default()
+                });
+                keys.clear();
+                continue;
+            }
+
+            let node = nodes
+                .last_mut()
+                .ok_or(format!("line {n}: key outside of a program table"))?;
+            let (key, value) = line
+                .split_once('=')
+                .ok_or(format!("line {n}: expected `key = value`"))?;
+            let key = key.trim();
+            if keys.iter().any(|k| k == key) {
+                return Err(format!("line {n}: duplicate key `{key}`"));
+            }
+            keys.push(key.into());
+            match (
+                key,
+                parse_value(value.trim()).map_err(|e| format!("line {n}: {e}"))?,
+            ) {
+                ("program", Value::Str(s)) => node.program = s.into(),
+                ("input", Value::Str(s)) => node.input = Some(s.into()),
+                ("inputs", Value::List(l)) => node.inputs = l,
+                ("output", Value::Str(s)) => node.output = Some(s.into()),
+                ("program" | "input" | "output", _) => {
+                    return Err(format!("line {n}: `{key}` must be a string"))
+                }
+                ("inputs", _) => return Err(format!("line {n}: `{key}` must be a list")),
+                _ => return Err(format!("line {n}: unknown key `{key}`")),
+            }
+        }
+        Self::validate(nodes)
+    }
+}
+
+impl Manifest {
+    /// Checks that the nodes form a valid graph, and sorts them in topological order.
+    fn validate(nodes: Vec<Node>) -> Result<Self, String> {
+        for node in &nodes {
+            if node.program.as_os_str().is_empty() {
+                return Err(format!("`{}` does not have a program", node.name));
+            }
+            if node.input.is_some() && !node.inputs.is_empty() {
+                return Err(format!(
+                    "`{}` cannot have both `input` and `inputs`",
+                    node.name
+                ));
+            }
+            if let Some(i) = node
+                .inputs
+                .iter()
+                .find(|i| !nodes.iter().any(|n| n.name == **i))
+            {
+                return Err(format!("`{}` has an unknown input `{i}`", node.name));
+            }
+        }
+
+        // Kahn's algorithm, which leaves behind the nodes that are part of a cycle
+        let mut pending: HashMap<&str, usize> = nodes
+            .iter()
+            .map(|n| (n.name.as_str(), n.inputs.len()))
+            .collect();
+        let mut ready: VecDeque<_> = nodes.iter().filter(|n| n.inputs.is_empty()).collect();
+        let mut sorted = Vec::with_capacity(nodes.len());
+        while let Some(node) = ready.pop_front() {
+            for next in &nodes {
+                for _ in next.inputs.iter().filter(|i| **i == node.name) {
+                    let count = pending.get_mut(next.name.as_str()).unwrap();
+                    *count -= 1;
+                    if *count == 0 {
+                        ready.push_back(next);
+                    }
+                }
+            }
+            sorted.push(node.clone());
+        }
+        if let Some(node) = nodes.iter().find(|n| pending[n.name.as_str()] > 0) {
+            return Err(format!("`{}` is part of a cycle", node.name));
+        }
+        Ok(Self { nodes: sorted })
+    }
+}
+
+fn is_comment(s: &str) -> bool {
+    let s = s.trim();
+    s.is_empty() || s.starts_with('#')
+}
+
+fn parse_value(s: &str) -> Result<Value, String> {
+    if let Some(mut s) = s.strip_prefix('[') {
+        let mut items = vec![];
+        loop {
+            s = s.trim_start();
+            if let Some(rest) = s.strip_prefix(']') {
+                s = rest;
+                break;
+            }
+            let (item, rest) = parse_str(s)?;
+            items.push(item);
+            s = rest.trim_start();
+            if let Some(rest) = s.strip_prefix(',') {
+                s = rest;
+            } else if !s.starts_with(']') {
+                return Err("expected `,` or `]` in list".into());
+            }
+        }
+        if !is_comment(s) {
+            return Err("unexpected characters after list".into());
+        }
+        Ok(Value::List(items))
+    } else {
+        let (item, rest) = parse_str(s)?;
+        if !is_comment(rest) {
+            return Err("unexpected characters after string".into());
+        }
+        Ok(Value::Str(item))
+    }
+}
+
+/// Parses a double-quoted string from the start of `s`, returning it along with the rest of `s`.
+fn parse_str(s: &str) -> Result<(String, &str), String> {
+    let mut chars = s
+        .strip_prefix('"')
+        .ok_or("expected a string")?
+        .char_indices();
+    let mut out = String::new();
+    while let Some((i, c)) = chars.next() {
+        match c {
+            '"' => return Ok((out, &s[i + 2..])),
+            '\\' => match chars.next() {
+                Some((_, '"')) => out.push('"'),
+                Some((_, '\\')) => out.push('\\'),
+                Some((_, 'n')) => out.push('\n'),
+                Some((_, 't')) => out.push('\t'),
+                _ => return Err("invalid escape sequence in string".into()),
+            },
+            c => out.push(c),
+        }
+    }
+    Err("unterminated string".into())
+}
+
+/// Reads from each reader in order until it is exhausted.
+struct Concat(VecDeque<Box<dyn Read + Send>>);
+
+impl Read for Concat {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        while let Some(reader) = self.0.front_mut() {
+            match reader.read(buf)? {
+                0 => self.0.pop_front(),
+                n => return Ok(n),
+            };
+        }
+        Ok(0)
+    }
+}
+
+/// Writes to every writer. A writer whose program has exited is dropped, so that the other writers
+/// still receive the output, which only reports a broken pipe once every writer is gone.
+struct Tee(Vec<Box<dyn Write + Send>>);
+
+impl Tee {
+    /// Runs `f` on every writer, dropping the writers with a broken pipe and stopping at any other
+    /// error.
+    fn retain(
+        &mut self,
+        mut f: impl FnMut(&mut Box<dyn Write + Send>) -> io::Result<()>,
+    ) -> io::Result<()> {
+        let mut i = 0;
+        while i < self.0.len() {
+            match f(&mut self.0[i]) {
+                Ok(()) => i += 1,
+                Err(e) if e.kind() == io::ErrorKind::BrokenPipe => {
+                    self.0.remove(i);
+                }
+                Err(e) => return Err(e),
+            }
+        }
+        if self.0.is_empty() {
+            return Err(io::ErrorKind::BrokenPipe.into());
+        }
+        Ok(())
+    }
+}
+
+impl Write for Tee {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.retain(|writer| writer.write_all(buf))?;
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.retain(|writer| writer.flush())
+    }
+}
+
+/// Runs every program in the manifest on its own thread and [`Cpu`](crate::Cpu), connecting the
+/// programs as described by the manifest. Paths in the manifest are resolved relative to `dir`.
+///
+/// Returns the statistics for each program in topological order, or `None` if it failed.
+pub fn pipeline(
+    manifest: &Manifest,
+    dir: &Path,
+    config: &Config,
+) -> Result<Vec<(String, Option<Stats>)>, String> {
+    let n = manifest.nodes.len();
+    let index: HashMap<&str, usize> = manifest
+        .nodes
+        .iter()
+        .enumerate()
+        .map(|(i, node)| (node.name.as_str(), i))
+        .collect();
+    let mut srcs = Vec::with_capacity(n);
+    let mut inputs: Vec<VecDeque<Box<dyn Read + Send>>> = (0..n).map(|_| VecDeque::new()).collect();
+    let mut outputs: Vec<Vec<Box<dyn Write + Send>>> = (0..n).map(|_| Vec::new()).collect();
+
+    for (i, node) in manifest.nodes.iter().enumerate() {
+        let path = dir.join(&node.program);
+        srcs.push(
+            fs::read_to_string(&path)
+                .map_err(|e| format!("failed to read `{}`: {e}", path.display()))?,
+        );
+        if let Some(input) = &node.input {
+            let path = dir.join(input);
+            let file = File::open(&path)
+                .map_err(|e| format!("failed to open `{}`: {e}", path.display()))?;
+            inputs[i].push_back(Box::new(file));
+        }
+        if let Some(output) = &node.output {
+            let path = dir.join(output);
+            let file = File::create(&path)
+                .map_err(|e| format!("failed to create `{}`: {e}", path.display()))?;
+            outputs[i].push(Box::new(io::BufWriter::new(file)));
+        }
+        for (k, input) in node.inputs.iter().enumerate() {
+            // Only the first input is read while its program is running, so the rest are
+            // unbounded to prevent programs that share an upstream program from deadlocking
+            let (writer, reader) = if k == 0 {
+                bounded_channel()
+            } else {
+                unbounded_channel()
+            };
+            outputs[index[input.as_str()]].push(Box::new(writer));
+            inputs[i].push_back(Box::new(reader));
+        }
+    }
+    for output in &mut outputs {
+        if output.is_empty() {
+            output.push(Box::new(io::stdout()));
+        }
+    }
+
+    Ok(thread::scope(|s| {
+        let handles: Vec<_> = srcs
+            .iter()
+            .zip(inputs.into_iter().zip(outputs))
+            .map(|(src, (input, output))| {
+                s.spawn(move || run_counted(src, config, Concat(input), Tee(output)))
+            })
+            .collect();
+        manifest
+            .nodes
+            .iter()
+            .zip(handles)
+            .map(|(node, h)| (node.name.clone(), h.join().ok()))
+            .collect()
+    }))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        fs,
+        io::{self, Write},
+    };
+
+    use super::{pipeline, Manifest, Tee};
+    use crate::Config;
+
+    #[test]
+    fn parse() {
+        let manifest: Manifest = r#"
+            # Comment
+            [b]
+            program = "b.b" # Comment
+            inputs = [ "a", ]
+
+            [a]
+            program = "a \"quoted\".b"
+            input = "a.in"
+            output = "a.out"
+        "#
+        .parse()
+        .unwrap();
+        let names: Vec<_> = manifest.nodes.iter().map(|n| n.name.as_str()).collect();
+        assert_eq!(names, ["a", "b"]);
+        assert_eq!(manifest.nodes[0].program.to_str(), Some("a \"quoted\".b"));
+        assert_eq!(manifest.nodes[1].inputs, ["a"]);
+    }
+
+    #[test]
+    fn invalid() {
+        let cases = [
+            ("program = \"a.b\"", "line 1: key outside of a program table"),
+            ("[a]\nprogram = a.b", "line 2: expected a string"),
+            ("[a]\nprogram = \"a.b\"\nfoo = \"bar\"", "line 3: unknown key `foo`"),
+            ("[a]\ninputs = [\"b\"]\nprogram = \"a.b\"", "`a` has an unknown input `b`"),
+            (
+                "[a]\nprogram = \"a.b\"\ninputs = [\"b\"]\n[b]\nprogram = \"b.b\"\ninputs = [\"a\"]",
+                "`a` is part of a cycle",
+            ),
+        ];
+        for (src, err) in cases {
+            assert_eq!(src.parse::<Manifest>(), Err(err.into()));
+        }
+    }
+
+    #[test]
+    fn diamond() {
+        let dir = std::env::temp_dir().join(format!("bri-pipeline-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        fs::write(dir.join("cat.b"), ",[.,]").unwrap();
+        fs::write(dir.join("inc.b"), ",[+.,]").unwrap();
+        fs::write(dir.join("in.txt"), "abc").unwrap();
+        let manifest: Manifest = r#"
+            [source]
+            program = "cat.b"
+            input = "in.txt"
+            [left]
+            program = "cat.b"
+            inputs = ["source"]
+            [right]
+            program = "inc.b"
+            inputs = ["source"]
+            [sink]
+            program = "cat.b"
+            inputs = ["left", "right"]
+            output = "out.txt"
+        "#
+        .parse()
+        .unwrap();
+        let stats = pipeline(&manifest, &dir, &Config::default()).unwrap();
+        let out = fs::read_to_string(dir.join("out.txt")).unwrap();
+        fs::remove_dir_all(&dir).unwrap();
+
+        assert_eq!(out, "abcbcd");
+        let written: Vec<_> = stats
+            .iter()
+            .map(|(name, s)| (name.as_str(), s.as_ref().map(|s| s.written)))
+            .collect();
+        assert_eq!(
+            written,
+            [
+                ("source", Some(3)),
+                ("left", Some(3)),
+                ("right", Some(3)),
+                ("sink", Some(6))
+            ]
+        );
+    }
+
+    #[test]
+    fn early_exit() {
+        let dir = std::env::temp_dir().join(format!("bri-early-exit-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        fs::write(dir.join("cat.b"), ",[.,]").unwrap();
+        fs::write(dir.join("one.b"), ",.").unwrap();
+        let input: Vec<u8> = (0..200_000).map(|i| b'a' + (i % 26) as u8).collect();
+        fs::write(dir.join("in.txt"), &input).unwrap();
+        // `left` exits after the first byte, which must not cut off the input of `right`
+        let manifest: Manifest = r#"
+            [source]
+            program = "cat.b"
+            input = "in.txt"
+            [left]
+            program = "one.b"
+            inputs = ["source"]
+            output = "left.txt"
+            [right]
+            program = "cat.b"
+            inputs = ["source"]
+            output = "right.txt"
+        "#
+        .parse()
+        .unwrap();
+        let stats = pipeline(&manifest, &dir, &Config::default()).unwrap();
+        let (left, right) = (
+            fs::read(dir.join("left.txt")).unwrap(),
+            fs::read(dir.join("right.txt")).unwrap(),
+        );
+        fs::remove_dir_all(&dir).unwrap();
+
+        assert_eq!(left, b"a");
+        assert_eq!(right, input);
+        assert!(stats.iter().all(|(_, s)| s.is_some()));
+    }
+
+    #[test]
+    fn tee() {
+        struct Failing(io::ErrorKind);
+        impl Write for Failing {
+            fn write(&mut self, _: &[u8]) -> io::Result<usize> {
+                Err(self.0.into())
+            }
+            fn flush(&mut self) -> io::Result<()> {
+                Ok(())
+            }
+        }
+
+        // A writer with a broken pipe is dropped, and the rest still receive the output
+        let mut tee = Tee(vec![
+            Box::new(Failing(io::ErrorKind::BrokenPipe)),
+            Box::new(Vec::new()),
+        ]);
+        assert_eq!(tee.write(b"abc").unwrap(), 3);
+        assert_eq!(tee.0.len(), 1);
+
+        // Any other error is not silently dropped
+        let mut tee = Tee(vec![
+            Box::new(Vec::new()),
+            Box::new(Failing(io::ErrorKind::StorageFull)),
+        ]);
+        assert_eq!(
+            tee.write(b"abc").unwrap_err().kind(),
+            io::ErrorKind::StorageFull
+        );
+
+        let mut tee = Tee(vec![Box::new(Failing(io::ErrorKind::BrokenPipe))]);
+        assert_eq!(
+            tee.write(b"abc").unwrap_err().kind(),
+            io::ErrorKind::BrokenPipe
+        );
+    }
+}
@@ -0,0 +1,299 @@
+//! A sampling profiler, which records the current instruction every N executed instructions and
+//! attributes the samples to spans of the source and to loops. The overhead is a single counter
+//! per executed instruction, which makes it suitable for very long runs.
+
+use std::{
+    cmp::Reverse,
+    fmt,
+    io::{Read, Write},
+};
+
+use crate::{
+    compile,
+    parse::{self, Jump, Op},
+    Cpu, Probe,
+};
+
+/// Number of instructions and loops shown in the report.
+const REPORT_LEN: usize = 10;
+/// Maximum number of characters of source shown for each entry in the report.
+const SNIPPET_LEN: usize = 24;
+
+/// Records the index of the current instruction every `period` executed instructions.
+struct Sampler {
+    period: usize,
+    countdown: usize,
+    samples: Vec<u32>,
+}
+
+impl Probe for Sampler {
+    #[inline(always)]
+    fn step(&mut self, i: usize) {
+        self.countdown -= 1;
+        if self.countdown == 0 {
+            self.countdown = self.period;
+            self.samples.push(i as u32);
+        }
+    }
+}
+
+/// A loop, identified by the positions of its `[` and `]`.
+#[derive(Clone, Debug, PartialEq, Eq)]
+struct Loop {
+    start: usize,
+    end: usize,
+    parent: Option<usize>,
+}
+
+#[derive(Clone, Debug)]
+pub struct Profile {
+    src: String,
+    ops: Vec<Op>,
+    offsets: Vec<usize>,
+    period: usize,
+    samples: Vec<u32>,
+}
+
+/// Runs the program on the CPU, sampling the current instruction every `period` executed
+/// instructions.
+pub fn profile<R: Read, W: Write>(src: &str, cpu: &mut Cpu<R, W>, period: usize) -> Profile {
+    assert!(period > 0, "the sampling period must be greater than 0");
+    let (ops, offsets) = compile(src);
+    let mut sampler = Sampler {
+        period,
+        countdown: period,
+        samples: Vec::default(),
+    };
+    cpu.exec_with(&ops, &mut sampler);
+    Profile {
+        src: src.into(),
+        ops,
+        offsets,
+        period,
+        samples: sampler.samples,
+    }
+}
+
+impl Profile {
+    /// Number of samples for each instruction.
+    fn counts(&self) -> Vec<usize> {
+        let mut counts = vec![0; self.ops.len()];
+        for &i in &self.samples {
+            counts[i as usize] += 1;
+        }
+        counts
+    }
+
+    /// Returns the loops in the order of their `[`, along with the innermost loop enclosing each
+    /// instruction. The jumps of a loop are enclosed by the loop itself.
+    fn loops(&self) -> (Vec<Loop>, Vec<Option<usize>>) {
+        let (mut loops, mut stack) = (Vec::<Loop>::new(), Vec::new());
+        let mut enclosing = Vec::with_capacity(self.ops.len());
+        for (i, op) in self.ops.iter().enumerate() {
+            match op {
+                Op::Jump(Jump::JumpR(_)) => {
+                    loops.push(Loop {
+                        start: i,
+                        end: i,
+                        parent: stack.last().copied(),
+                    });
+                    stack.push(loops.len() - 1);
+                    enclosing.push(stack.last().copied());
+                }
+                Op::Jump(Jump::JumpL(_)) => {
+                    enclosing.push(stack.last().copied());
+                    let id = stack.pop().expect("jumps have already been resolved");
+                    loops[id].end = i;
+                }
+                _ => enclosing.push(stack.last().copied()),
+            }
+        }
+        (loops, enclosing)
+    }
+
+    /// Number of samples in each loop, including and excluding the loops nested within it.
+    fn loop_counts(&self, loops: &[Loop], enclosing: &[Option<usize>]) -> Vec<(usize, usize)> {
+        let mut counts = vec![(0, 0); loops.len()];
+        for &i in &self.samples {
+            let mut id = enclosing[i as usize];
+            if let Some(id) = id {
+                counts[id].1 += 1;
+            }
+            while let Some(l) = id {
+                counts[l].0 += 1;
+                id = loops[l].parent;
+            }
+        }
+        counts
+    }
+
+    /// Formats the samples as folded stacks, which can be rendered as a flamegraph by standard
+    /// tooling like `flamegraph.pl` or `inferno`. The stack of each sample is the path of loops
+    /// enclosing the sampled instruction, with each loop named by the location of its `[`.
+    pub fn folded(&self) -> String {
+        let (loops, enclosing) = self.loops();
+        // Samples per innermost enclosing loop, with samples outside of any loop at the end
+        let mut counts = vec![0; loops.len() + 1];
+        for &i in &self.samples {
+            counts[enclosing[i as usize].unwrap_or(loops.len())] += 1;
+        }
+
+        let mut out = String::new();
+        if counts[loops.len()] > 0 {
+            out.push_str(&format!("main {}\n", counts[loops.len()]));
+        }
+        for (id, &n) in counts[..loops.len()].iter().enumerate() {
+            if n == 0 {
+                continue;
+            }
+            let mut stack = vec![];
+            let mut l = Some(id);
+            while let Some(id) = l {
+                let (line, col) = self.location(self.offsets[loops[id].start]);
+                stack.push(format!("loop@{line}:{col}"));
+                l = loops[id].parent;
+            }
+            stack.push("main".into());
+            stack.reverse();
+            out.push_str(&format!("{} {n}\n", stack.join(";")));
+        }
+        out
+    }
+
+    fn location(&self, offset: usize) -> (usize, usize) {
+        parse::location(&self.src, offset)
+    }
+
+    /// The instructions in the source between the instructions at `start` and `end`, inclusive,
+    /// along with any instructions folded into the one at `end`.
+    fn snippet(&self, start: usize, end: usize) -> String {
+        let end = self.offsets.get(end + 1).copied().unwrap_or(self.src.len());
+        let ops: String = self.src[self.offsets[start]..end]
+            .chars()
+            .filter(|c| Op::try_from(*c).is_ok())
+            .collect();
+        if ops.chars().count() > SNIPPET_LEN {
+            format!("{}...", ops.chars().take(SNIPPET_LEN).collect::<String>())
+        } else {
+            ops
+        }
+    }
+}
+
+impl fmt::Display for Profile {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let total = self.samples.len();
+        writeln!(
+            f,
+            "Sampled every {} instructions ({total} samples)",
+            self.period
+        )?;
+        if total == 0 {
+            return writeln!(f, "No samples were recorded, try a smaller sampling period");
+        }
+        let percent = |n: usize| n as f64 * 100.0 / total as f64;
+
+        let mut ops: Vec<_> = self
+            .counts()
+            .into_iter()
+            .enumerate()
+            .filter(|(_, n)| *n > 0)
+            .collect();
+        ops.sort_by_key(|(_, n)| Reverse(*n));
+        writeln!(f, "\nHottest instructions:")?;
+        writeln!(f, "{:>10} {:>7}  {:<10} Source", "Samples", "%", "Location")?;
+        for (i, n) in ops.into_iter().take(REPORT_LEN) {
+            let (line, col) = self.location(self.offsets[i]);
+            writeln!(
+                f,
+                "{n:>10} {:>6.2}%  {:<10} {}",
+                percent(n),
+                format!("{line}:{col}"),
+                self.snippet(i, i)
+            )?;
+        }
+
+        let (loops, enclosing) = self.loops();
+        let mut counts: Vec<_> = self
+            .loop_counts(&loops, &enclosing)
+            .into_iter()
+            .zip(&loops)
+            .filter(|((n, _), _)| *n > 0)
+            .collect();
+        if counts.is_empty() {
+            return Ok(());
+        }
+        counts.sort_by_key(|((n, _), _)| Reverse(*n));
+        writeln!(f, "\nHottest loops:")?;
+        writeln!(
+            f,
+            "{:>10} {:>7} {:>10}  {:<16} Source",
+            "Samples", "%", "Self", "Location"
+        )?;
+        for ((n, self_n), l) in counts.into_iter().take(REPORT_LEN) {
+            let (start, end) = (
+                self.location(self.offsets[l.start]),
+                self.location(self.offsets[l.end]),
+            );
+            writeln!(
+                f,
+                "{n:>10} {:>6.2}% {self_n:>10}  {:<16} {}",
+                percent(n),
+                format!("{}:{}-{}:{}", start.0, start.1, end.0, end.1),
+                self.snippet(l.start, l.end)
+            )?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{Config, Cpu};
+
+    #[test]
+    fn attribution() {
+        let mut cpu = Cpu::with_io(Config::default(), &b""[..], Vec::new());
+        let profile = super::profile("+++\n[>++>[-]<<-]>.", &mut cpu, 1);
+        assert_eq!(cpu.output(), &[6]);
+        // The loop body executes 3 times, with the `[` executed only once
+        assert_eq!(profile.samples.len(), 1 + 1 + 3 * 7 + 2);
+        assert_eq!(profile.counts(), [1, 1, 3, 3, 3, 3, 3, 3, 3, 1, 1]);
+
+        let (loops, enclosing) = profile.loops();
+        assert_eq!(
+            loops,
+            [super::Loop {
+                start: 1,
+                end: 8,
+                parent: None
+            }]
+        );
+        assert_eq!(profile.loop_counts(&loops, &enclosing), [(22, 22)]);
+        assert_eq!(profile.location(profile.offsets[1]), (2, 1));
+        // The clear loop is folded into a single instruction
+        assert_eq!(profile.snippet(5, 5), "[-]");
+    }
+
+    #[test]
+    fn nested_loops() {
+        let mut cpu = Cpu::with_io(Config::default(), &b""[..], Vec::new());
+        let profile = super::profile("++[>++[>+<-]<-]>>.", &mut cpu, 1);
+        let (loops, enclosing) = profile.loops();
+        assert_eq!(loops[1].parent, Some(0));
+        let counts = profile.loop_counts(&loops, &enclosing);
+        // The outer loop includes the samples of the inner loop
+        assert_eq!(counts[0].0, counts[0].1 + counts[1].0);
+        assert_eq!(counts[1].0, counts[1].1);
+    }
+
+    #[test]
+    fn folded() {
+        let mut cpu = Cpu::with_io(Config::default(), &b""[..], Vec::new());
+        let profile = super::profile("++[>++\n[>+<-]<-]>>.", &mut cpu, 1);
+        assert_eq!(
+            profile.folded(),
+            "main 3\nmain;loop@1:3 11\nmain;loop@1:3;loop@2:1 22\n"
+        );
+    }
+}
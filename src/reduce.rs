@@ -0,0 +1,78 @@
+use std::collections::HashMap;
+
+use crate::parse::Op;
+
+/// Shrinks a program down to a minimal one that is still interesting to the oracle, e.g. one
+/// that still triggers a divergence between the optimised and unoptimised runs. The oracle must
+/// consider the original program interesting.
+///
+/// Comments are stripped first if the program remains interesting without them, after which
+/// delta debugging (ddmin) removes chunks of instructions until no single instruction can be
+/// removed.
+pub fn reduce(src: &str, mut oracle: impl FnMut(&str) -> bool) -> String {
+    // The oracle is usually expensive, so never ask it about the same program twice
+    let mut cache = HashMap::new();
+    let mut test = |chars: &[char]| -> bool {
+        let candidate: String = chars.iter().collect();
+        *cache
+            .entry(candidate)
+            .or_insert_with_key(|candidate| oracle(candidate))
+    };
+
+    let mut chars: Vec<char> = src.chars().collect();
+    let stripped: Vec<char> = chars
+        .iter()
+        .copied()
+        .filter(|c| Op::try_from(*c).is_ok())
+        .collect();
+    if stripped.len() < chars.len() && test(&stripped) {
+        chars = stripped;
+    }
+
+    let mut n = 2;
+    'outer: while chars.len() >= 2 {
+        let size = chars.len().div_ceil(n);
+        let chunks: Vec<_> = (0..chars.len()).step_by(size).collect();
+        // Try to reduce to a single chunk
+        for &start in &chunks {
+            let subset = &chars[start..(start + size).min(chars.len())];
+            if test(subset) {
+                chars = subset.to_vec();
+                n = 2;
+                continue 'outer;
+            }
+        }
+        // Try to remove a single chunk
+        for &start in &chunks {
+            let complement: Vec<_> =
+                [&chars[..start], &chars[(start + size).min(chars.len())..]].concat();
+            if test(&complement) {
+                chars = complement;
+                n = (n - 1).max(2);
+                continue 'outer;
+            }
+        }
+        if n >= chars.len() {
+            break;
+        }
+        n = (n * 2).min(chars.len());
+    }
+    chars.into_iter().collect()
+}
+
+#[cfg(test)]
+mod tests {
+    #[test]
+    fn minimal() {
+        let src = "+++[>++<-]>[-]<<+[-].";
+        assert_eq!(super::reduce(src, |s| s.contains("[-]")), "[-]");
+    }
+
+    #[test]
+    fn strip_comments() {
+        let src = "clear the cell: [-] and print it: .";
+        assert_eq!(super::reduce(src, |s| s.ends_with("[-].")), "[-].");
+        // Comments that the oracle depends on are retained
+        assert_eq!(super::reduce(src, |s| s.contains("it")), "it");
+    }
+}
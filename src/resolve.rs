@@ -21,7 +21,7 @@ pub fn resolve_jumps(ops: &mut [Op]) {
                                 unreachable!("left jumps cannot be present on the stack");
                             }
                         })
-                        .expect(&format!("unmatched `]` at position {}", i + 1));
+                        .unwrap_or_else(|| panic!("unmatched `]` at position {}", i + 1));
                     // Insert the jump positions into the right and left jump instructions
                     (*r, *l) = (i + 1, *r + 1);
                 }
@@ -64,12 +64,12 @@ mod tests {
     #[test]
     #[should_panic]
     fn mismatched_jump_r() {
-        resolve_jumps(&mut vec![Op::Jump(Jump::JumpR(0))]);
+        resolve_jumps(&mut [Op::Jump(Jump::JumpR(0))]);
     }
 
     #[test]
     #[should_panic]
     fn mismatched_jump_l() {
-        resolve_jumps(&mut vec![Op::Jump(Jump::JumpL(0))]);
+        resolve_jumps(&mut [Op::Jump(Jump::JumpL(0))]);
     }
 }
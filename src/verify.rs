@@ -0,0 +1,279 @@
+//! Verification of the optimisations, by comparing the outcomes of the unoptimised and optimised
+//! runs of a program.
+
+use std::{
+    fmt,
+    panic::{self, AssertUnwindSafe},
+};
+
+use crate::{
+    compile_with,
+    optimise::{Pass, PASSES},
+    parse::{self, Op},
+    Config, Cpu, Probe,
+};
+
+/// Number of unchanged instructions shown around each change in the IR diff.
+const DIFF_CONTEXT: usize = 3;
+/// Maximum number of hunks shown in the IR diff.
+const DIFF_HUNKS: usize = 5;
+
+/// The outcome of running a program.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Outcome {
+    pub output: Vec<u8>,
+    /// The panic message, if the run panicked.
+    pub panic: Option<String>,
+    /// Whether the run was stopped for executing more instructions than the limit.
+    pub timed_out: bool,
+}
+
+/// A divergence between the unoptimised and optimised runs of a program.
+#[derive(Clone, Debug)]
+pub struct Divergence {
+    pub expected: Outcome,
+    pub actual: Outcome,
+    bisection: Option<Bisection>,
+}
+
+/// The first pass in the pipeline that introduces a divergence.
+#[derive(Clone, Debug)]
+struct Bisection {
+    pass: usize,
+    src: String,
+    offsets: Vec<usize>,
+    /// The instructions before running the pass.
+    before: Vec<Op>,
+    /// The instructions after running the pass, or `None` if the pass panicked.
+    after: Option<Vec<Op>>,
+}
+
+/// Stops a run by unwinding with [`OutOfSteps`] once it has executed its budget of instructions.
+struct Budget(u64);
+
+struct OutOfSteps;
+
+impl Probe for Budget {
+    #[inline(always)]
+    fn step(&mut self, _: usize) {
+        if self.0 == 0 {
+            panic::panic_any(OutOfSteps);
+        }
+        self.0 -= 1;
+    }
+}
+
+/// Runs the program with and without optimisations on the same input, and compares the outcomes.
+/// Each run is stopped after executing `limit` instructions, and fails if the unoptimised run does
+/// not finish within the limit. Every optimised instruction replaces one or more unoptimised
+/// instructions, so a correctly optimised run never needs more instructions than the unoptimised
+/// one, and a run that is stopped diverges.
+///
+/// If they diverge and `bisect` is set, the program is re-run with increasing prefixes of the
+/// pass pipeline to find the first pass that introduces the divergence.
+///
+/// Panics in the runs are caught and recorded in the outcomes, but are still reported by the panic
+/// hook, which callers may want to silence.
+pub fn verify(
+    src: &str,
+    config: &Config,
+    input: &[u8],
+    limit: u64,
+    bisect: bool,
+) -> Result<Option<Divergence>, String> {
+    let run = |passes: usize| run(src, &PASSES[..passes], config, input, limit);
+    let expected = run(0);
+    if expected.timed_out {
+        return Err(format!(
+            "the unoptimised run did not finish within {limit} instructions"
+        ));
+    }
+    let actual = run(PASSES.len());
+    Ok((expected != actual).then(|| {
+        let bisection = bisect.then(|| {
+            let pass = (1..PASSES.len())
+                .find(|&n| run(n) != expected)
+                .unwrap_or(PASSES.len())
+                - 1;
+            let (mut ops, offsets) = parse::parse(src);
+            for p in &PASSES[..pass] {
+                (p.run)(&mut ops);
+            }
+            let before = ops.clone();
+            let after = panic::catch_unwind(AssertUnwindSafe(|| (PASSES[pass].run)(&mut ops)))
+                .ok()
+                .map(|_| ops);
+            Bisection {
+                pass,
+                src: src.into(),
+                offsets,
+                before,
+                after,
+            }
+        });
+        Divergence {
+            expected,
+            actual,
+            bisection,
+        }
+    }))
+}
+
+/// Runs the program with the given passes, stopping after executing `limit` instructions.
+fn run(src: &str, passes: &[Pass], config: &Config, input: &[u8], limit: u64) -> Outcome {
+    let mut output = Vec::new();
+    let result = panic::catch_unwind(AssertUnwindSafe(|| {
+        let (ops, _) = compile_with(src, passes);
+        Cpu::with_io(config.clone(), input, &mut output).exec_with(&ops, &mut Budget(limit));
+    }));
+    let (panic, timed_out) = match result {
+        Ok(()) => (None, false),
+        Err(e) if e.is::<OutOfSteps>() => (None, true),
+        Err(e) => (
+            Some(
+                e.downcast_ref::<&str>()
+                    .map(|s| s.to_string())
+                    .or_else(|| e.downcast_ref::<String>().cloned())
+                    .unwrap_or_default(),
+            ),
+            false,
+        ),
+    };
+    Outcome {
+        output,
+        panic,
+        timed_out,
+    }
+}
+
+impl Divergence {
+    /// The name of the first pass that introduces the divergence, if the passes were bisected.
+    pub fn pass(&self) -> Option<&'static str> {
+        self.bisection.as_ref().map(|b| PASSES[b.pass].name)
+    }
+}
+
+impl fmt::Display for Outcome {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "wrote {} bytes", self.output.len())?;
+        match &self.panic {
+            Some(msg) => write!(f, ", then panicked: {msg}"),
+            None if self.timed_out => write!(f, ", then ran past the instruction limit"),
+            None => Ok(()),
+        }
+    }
+}
+
+impl fmt::Display for Divergence {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        writeln!(f, "The optimised run diverges from the unoptimised run")?;
+        writeln!(f, "Unoptimised: {}", self.expected)?;
+        writeln!(f, "Optimised:   {}", self.actual)?;
+        if self.expected.output != self.actual.output {
+            let n = self
+                .expected
+                .output
+                .iter()
+                .zip(&self.actual.output)
+                .take_while(|(a, b)| a == b)
+                .count();
+            writeln!(f, "The outputs first differ at byte {n}")?;
+        }
+        match &self.bisection {
+            Some(b) => write!(f, "{b}"),
+            None => Ok(()),
+        }
+    }
+}
+
+impl fmt::Display for Bisection {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        writeln!(
+            f,
+            "\nFirst diverging pass: {} ({} of {})",
+            PASSES[self.pass].name,
+            self.pass + 1,
+            PASSES.len()
+        )?;
+        let Some(after) = &self.after else {
+            return writeln!(f, "The pass panicked");
+        };
+
+        // Passes only replace instructions in place, so the instructions remain aligned
+        let mut hunks: Vec<(usize, usize)> = vec![];
+        for i in (0..self.before.len()).filter(|&i| self.before[i] != after[i]) {
+            let (start, end) = (
+                i.saturating_sub(DIFF_CONTEXT),
+                (i + DIFF_CONTEXT + 1).min(self.before.len()),
+            );
+            match hunks.last_mut() {
+                Some(hunk) if start <= hunk.1 => hunk.1 = end,
+                _ => hunks.push((start, end)),
+            }
+        }
+        if hunks.is_empty() {
+            return writeln!(f, "The pass did not change any instructions");
+        }
+        writeln!(f, "IR diff:")?;
+        for &(start, end) in hunks.iter().take(DIFF_HUNKS) {
+            writeln!(f, "@@")?;
+            let ops = self.before.iter().zip(after).zip(&self.offsets);
+            for ((old, new), &offset) in ops.take(end).skip(start) {
+                let (line, col) = parse::location(&self.src, offset);
+                let location = format!("{line}:{col}");
+                if old == new {
+                    writeln!(f, "  {location:<10} {old}")?;
+                } else {
+                    writeln!(f, "- {location:<10} {old}")?;
+                    writeln!(f, "+ {location:<10} {new}")?;
+                }
+            }
+        }
+        if hunks.len() > DIFF_HUNKS {
+            writeln!(f, "... and {} more hunks", hunks.len() - DIFF_HUNKS)?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{optimise::PASSES, Config};
+
+    #[test]
+    fn no_divergence() {
+        assert!(
+            super::verify("++[>+<-]>.", &Config::default(), b"", 100, true)
+                .is_ok_and(|d| d.is_none())
+        );
+    }
+
+    #[test]
+    fn limit() {
+        // The cell is always odd, so the loop never ends
+        let outcome = super::run("+[--]+++.", PASSES, &Config::default(), b"", 1000);
+        assert!(outcome.timed_out && outcome.panic.is_none());
+        assert_eq!(
+            super::verify("+[--]+++.", &Config::default(), b"", 1000, true).map(|_| ()),
+            Err("the unoptimised run did not finish within 1000 instructions".into())
+        );
+    }
+
+    #[test]
+    fn bisect() {
+        // The nested loop after the dead loop is only partially removed
+        let divergence = super::verify("+[>][+[>]].", &Config::default(), b"", 100, true)
+            .unwrap()
+            .unwrap();
+        assert_eq!(divergence.expected.output, [0]);
+        assert!(divergence
+            .actual
+            .panic
+            .as_ref()
+            .is_some_and(|msg| msg.contains("unmatched")));
+        assert_eq!(divergence.pass(), Some("remove_dead_loops"));
+        assert!(divergence
+            .to_string()
+            .contains("- 1:8        >1\n+ 1:8        empty\n"));
+    }
+}